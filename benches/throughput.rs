@@ -0,0 +1,68 @@
+//! Compares the throughput of proxying plain TCP with splice(2) against copying through userspace.
+//!
+//! Run with `cargo bench --bench throughput`.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Total bytes sent through the proxy for each run.
+const TRANSFER_SIZE: usize = 1024 * 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+const RUNS: u32 = 5;
+
+/// sink accepts a single connection and reads until EOF.
+async fn sink() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; CHUNK_SIZE];
+        while stream.read(&mut buf).await.unwrap() > 0 {}
+    });
+    addr
+}
+
+/// proxy accepts a single connection and proxies it to upstream.
+async fn proxy(upstream: SocketAddr, use_splice: bool) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut downstream, _) = listener.accept().await.unwrap();
+        let mut upstream = TcpStream::connect(upstream).await.unwrap();
+        let transfer = ztunnel::proxy::Transfer::new(Duration::MAX);
+        ztunnel::proxy::copy_tcp(&mut downstream, &mut upstream, use_splice, &transfer)
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+async fn run(use_splice: bool) -> Duration {
+    let addr = proxy(sink().await, use_splice).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let chunk = vec![0xAB; CHUNK_SIZE];
+    let start = Instant::now();
+    for _ in 0..TRANSFER_SIZE / CHUNK_SIZE {
+        client.write_all(&chunk).await.unwrap();
+    }
+    client.shutdown().await.unwrap();
+    // The proxy closes its side once the sink has read everything.
+    client.read_to_end(&mut Vec::new()).await.unwrap();
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    for (name, use_splice) in [("userspace copy", false), ("splice", true)] {
+        let mut total = Duration::ZERO;
+        for _ in 0..RUNS {
+            total += run(use_splice).await;
+        }
+        let elapsed = total / RUNS;
+        let throughput = TRANSFER_SIZE as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+        println!("{name:>16}: {elapsed:>10.2?} per GiB, {throughput:>8.1} MiB/s");
+    }
+}
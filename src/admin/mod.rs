@@ -23,6 +23,7 @@ use tokio::fs::File;
 #[cfg(feature = "gperftools")]
 use tokio::io::AsyncReadExt;
 
+use crate::proxy::{ConnectionLimiter, Pool};
 use crate::workload::WorkloadInformation;
 use tracing::info;
 
@@ -30,6 +31,8 @@ use tracing::info;
 pub struct Builder {
     addr: SocketAddr,
     workload_info: WorkloadInformation,
+    pool: Option<Pool>,
+    limiter: Option<ConnectionLimiter>,
     ready: Readiness,
 }
 
@@ -38,6 +41,8 @@ pub struct Server {
     ready: Readiness,
     server: hyper::server::Builder<hyper::server::conn::AddrIncoming>,
     workload_info: WorkloadInformation,
+    pool: Option<Pool>,
+    limiter: Option<ConnectionLimiter>,
 }
 
 #[derive(Clone, Debug)]
@@ -49,22 +54,46 @@ impl Builder {
             addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15021),
             ready: Readiness(Arc::new(false.into())),
             workload_info: f,
+            pool: None,
+            limiter: None,
         }
     }
 
+    pub fn set_addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
     pub fn set_ready(self) -> Self {
         self.ready.set(true);
         self
     }
 
+    /// set_pool exposes the statistics of the outbound connection pool.
+    pub fn set_pool(mut self, pool: Pool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// set_limiter exposes the statistics of the outbound connection limiter.
+    pub fn set_limiter(mut self, limiter: ConnectionLimiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
     pub fn bind(self) -> hyper::Result<Server> {
         let Self {
             addr,
             ready,
             workload_info,
+            pool,
+            limiter,
         } = self;
 
-        let server = hyper::server::Server::try_bind(&addr)?
+        let incoming = hyper::server::conn::AddrIncoming::bind(&addr)?;
+        // Report the address actually bound, in case an ephemeral port was requested.
+        let addr = incoming.local_addr();
+        let server = hyper::server::Server::builder(incoming)
             .http1_half_close(true)
             .http1_header_read_timeout(Duration::from_secs(2))
             .http1_max_buf_size(8 * 1024);
@@ -74,24 +103,36 @@ impl Builder {
             ready,
             server,
             workload_info,
+            pool,
+            limiter,
         })
     }
 }
 
 impl Server {
+    pub fn address(&self) -> SocketAddr {
+        self.addr
+    }
+
     pub fn spawn(self) {
         let ready = self.ready.clone();
         let workload_info = self.workload_info.clone();
+        let pool = self.pool.clone();
+        let limiter = self.limiter.clone();
         let server = self
             .server
             .serve(hyper::service::make_service_fn(move |_conn| {
                 let ready = ready.clone();
                 let workload_info = workload_info.clone();
+                let pool = pool.clone();
+                let limiter = limiter.clone();
                 async move {
                     let workload_info = workload_info.clone();
                     Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
                         let ready = ready.clone();
                         let workload_info = workload_info.clone();
+                        let pool = pool.clone();
+                        let limiter = limiter.clone();
                         async move {
                             match req.uri().path() {
                                 "/healthz/ready" => {
@@ -109,6 +150,13 @@ impl Server {
                                 "/config_dump" => Ok::<_, hyper::Error>(
                                     handle_config_dump(workload_info, req).await,
                                 ),
+                                "/debug/pool" => {
+                                    Ok::<_, hyper::Error>(handle_pool_stats(pool, req).await)
+                                }
+                                "/debug/limits" => {
+                                    Ok::<_, hyper::Error>(handle_limit_stats(limiter, req).await)
+                                }
+                                "/metrics" => Ok::<_, hyper::Error>(handle_metrics(req).await),
                                 _ => Ok::<_, hyper::Error>(
                                     Response::builder()
                                         .status(hyper::StatusCode::NOT_FOUND)
@@ -193,6 +241,50 @@ async fn handle_config_dump(dump: WorkloadInformation, _req: Request<Body>) -> R
         .unwrap()
 }
 
+async fn handle_pool_stats(pool: Option<Pool>, _req: Request<Body>) -> Response<Body> {
+    let Some(pool) = pool else {
+        return Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body("connection pool not enabled".into())
+            .unwrap();
+    };
+    let vec = serde_json::to_vec(&pool.stats()).unwrap();
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(vec.into())
+        .unwrap()
+}
+
+async fn handle_limit_stats(
+    limiter: Option<ConnectionLimiter>,
+    _req: Request<Body>,
+) -> Response<Body> {
+    let Some(limiter) = limiter else {
+        return Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body("connection limits not enabled".into())
+            .unwrap();
+    };
+    let vec = serde_json::to_vec(&limiter.stats()).unwrap();
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(vec.into())
+        .unwrap()
+}
+
+async fn handle_metrics(_req: Request<Body>) -> Response<Body> {
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(
+            hyper::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )
+        .body(crate::metrics::encode().into())
+        .unwrap()
+}
+
 #[cfg(feature = "gperftools")]
 async fn handle_gprof(_req: Request<Body>) -> Response<Body> {
     const FILE_PATH: &str = "/tmp/profile.prof";
@@ -250,3 +342,36 @@ async fn handle_gprof_heap(_req: Request<Body>) -> Response<Body> {
         .body("gperftools not enabled".into())
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::workload::WorkloadStore;
+
+    #[tokio::test]
+    async fn metrics() {
+        crate::metrics::LISTENER_ERRORS.inc(vec![("listener", "admin-test".to_string())]);
+        let workloads = WorkloadInformation {
+            info: Arc::new(Mutex::new(WorkloadStore::default())),
+            demand: None,
+        };
+        let server = Builder::new(workloads)
+            .set_addr("127.0.0.1:0".parse().unwrap())
+            .bind()
+            .unwrap();
+        let addr = server.address();
+        server.spawn();
+
+        let res = hyper::Client::new()
+            .get(format!("http://{addr}/metrics").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("# TYPE istio_tcp_sent_bytes_total counter\n"));
+        assert!(body.contains("ztunnel_listener_errors_total{listener=\"admin-test\"} 1\n"));
+    }
+}
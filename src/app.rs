@@ -1,4 +1,6 @@
-use crate::{admin, config, identity, proxy, signal, workload};
+use crate::telemetry::trace;
+use crate::{admin, config, dns, identity, proxy, signal, socket, workload};
+use std::sync::Arc;
 use tokio::task::JoinHandle;
 use tokio::time;
 use tracing::{error, info, warn};
@@ -12,21 +14,59 @@ pub async fn spawn(shutdown: signal::Shutdown, config: config::Config) -> anyhow
     let mut tasks: Vec<JoinHandle<()>> = Vec::new();
     let workload_manager = workload::WorkloadManager::new(config.clone());
 
+    let workloads = workload_manager.workloads();
+    let secrets = identity::SecretManager::new(config.clone());
+    let mut record_sinks: Vec<Arc<dyn proxy::RecordSink>> = vec![Arc::new(proxy::TcpMetrics)];
+    if let Some(access_log) = &config.access_log {
+        record_sinks.push(Arc::new(proxy::AccessLog::new(access_log)?));
+    }
+    if let Some(endpoint) = &config.otlp_endpoint {
+        info!("exporting traces to {}", endpoint);
+        trace::init(endpoint.clone(), config.trace_sampling, config.otlp_timeout);
+    }
+    let proxy = proxy::Proxy::new(
+        config.clone(),
+        workloads,
+        secrets,
+        proxy::Recorder::new(record_sinks),
+        drain_rx.clone(),
+    )
+    .await?;
+    let connections = proxy.connections();
+    let dns = match config.dns_proxy_addr {
+        Some(addr) => Some(
+            dns::Server::new(
+                addr,
+                config.dns_upstreams.clone(),
+                socket::Dialer {
+                    mark: config.socket_mark,
+                    transparent: false,
+                },
+                workload_manager.workloads(),
+                drain_rx,
+            )
+            .await?,
+        ),
+        None => None,
+    };
     let workloads = workload_manager.workloads();
     admin::Builder::new(workloads)
+        .set_addr(config.admin_addr)
+        .set_pool(proxy.pool())
+        .set_limiter(proxy.limiter())
         .set_ready()
         .bind()
         .expect("admin server starts")
         .spawn();
-    let workloads = workload_manager.workloads();
-    let secrets = identity::SecretManager::new(config.clone());
-    let proxy = proxy::Proxy::new(config.clone(), workloads, secrets, drain_rx).await?;
     tasks.push(tokio::spawn(async move {
         if let Err(e) = workload_manager.run().await {
             error!("workload manager: {}", e);
         }
     }));
     tasks.push(tokio::spawn(proxy.run()));
+    if let Some(dns) = dns {
+        tasks.push(tokio::spawn(dns.run()));
+    }
 
     tokio::spawn(async move {
         futures::future::join_all(tasks).await;
@@ -37,13 +77,15 @@ pub async fn spawn(shutdown: signal::Shutdown, config: config::Config) -> anyhow
     shutdown.wait().await;
 
     // Start a drain; this will wait for all drain_rx handles to be dropped before completing,
-    // allowing components to terminate.
+    // allowing components to terminate. Listeners stop accepting, and each open connection holds
+    // a handle until it completes.
     // If they take too long, terminate anyways.
     match time::timeout(config.termination_grace_period, drain_tx.drain()).await {
         Ok(()) => info!("Shutdown completed gracefully"),
         Err(_) => warn!(
-            "Graceful shutdown did not complete in {:?}, terminating now",
-            config.termination_grace_period
+            "Graceful shutdown did not complete in {:?}, terminating now; {} connections forcibly closed",
+            config.termination_grace_period,
+            connections.active()
         ),
     }
     Ok(())
@@ -1,23 +1,78 @@
-use crate::identity;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-use std::path::PathBuf;
+//! Configuration is layered from, in increasing order of precedence: built-in defaults, an optional
+//! YAML config file, environment variables and command-line flags. Every setting has a key in the
+//! config file, such as `connect_timeout`, a flag derived from it, `--connect-timeout`, and an
+//! environment variable, listed by `--help`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
+
 use tokio::time;
 
+use crate::identity;
+use crate::proxy::{
+    AccessLogConfig, AccessLogFormat, AccessLogTemplate, UnknownSourcePolicy,
+    DEFAULT_ACCESS_LOG_FORMAT,
+};
+use crate::socket;
+use crate::workload::lb;
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub tls: bool,
 
+    /// The cluster this ztunnel runs in, as shared with peers in HBONE baggage.
+    pub cluster_id: String,
+    /// The DNS domain of the cluster. Workloads are resolvable by the DNS proxy as
+    /// <name>.<namespace>.svc.<cluster_domain>.
+    pub cluster_domain: String,
+
     pub window_size: u32,
     pub connection_window_size: u32,
     pub frame_size: u32,
+    /// If true, HTTP/2 flow-control windows grow with the measured bandwidth-delay product of each
+    /// HBONE connection, rather than staying at the fixed window sizes above.
+    pub http2_adaptive_window: bool,
+    /// How often HTTP/2 PING frames are sent on an HBONE connection that has received nothing, to
+    /// detect dead peers. If unset, no pings are sent.
+    pub http2_keepalive_interval: Option<Duration>,
+    /// How long to wait for a PING to be acknowledged before closing the connection.
+    pub http2_keepalive_timeout: Duration,
+    /// The maximum number of concurrent streams the inbound server allows on an HBONE connection.
+    pub http2_max_concurrent_streams: u32,
+    /// The maximum size of the headers of an HBONE request or response, counted as in HTTP/2's
+    /// SETTINGS_MAX_HEADER_LIST_SIZE.
+    pub http2_max_header_size: usize,
+
+    /// If true, plain TCP is proxied with splice(2) where supported, rather than copied through userspace.
+    pub splice: bool,
+
+    /// The maximum number of HBONE streams multiplexed onto a single pooled connection.
+    pub pool_max_streams_per_conn: usize,
+    /// How long a pooled connection with no active streams is kept before being closed.
+    pub pool_idle_timeout: Duration,
 
     pub inbound_addr: SocketAddr,
     pub inbound_plaintext_addr: SocketAddr,
     pub outbound_addr: SocketAddr,
+    /// The address of the DNS proxy, if enabled.
+    pub dns_proxy_addr: Option<SocketAddr>,
+    /// Resolvers queries the DNS proxy cannot answer are forwarded to. If empty, the nameservers
+    /// from /etc/resolv.conf are used.
+    pub dns_upstreams: Vec<SocketAddr>,
+    /// The address of the SOCKS5 listener for clients that are not transparently captured, if enabled.
+    /// Only clients on this node are served, and by default it only listens on loopback.
+    pub socks5_addr: Option<SocketAddr>,
+    /// The address of the admin server, which serves readiness, metrics and debug endpoints.
+    pub admin_addr: SocketAddr,
 
     /// The name of the node this ztunnel is running as.
     pub local_node: Option<String>,
+    /// Addresses, in addition to workloads on the local node, that inbound HBONE may connect to.
+    pub inbound_allowed_addresses: Vec<IpAddr>,
 
     /// Filepath to a local xds file for workloads, as YAML.
     pub local_xds_path: Option<String>,
@@ -26,33 +81,1044 @@ pub struct Config {
 
     pub auth: identity::AuthSource,
 
+    /// The strategy used to pick an endpoint for a VIP.
+    pub lb_strategy: lb::Strategy,
+    /// If true, endpoints closest to the source workload (by node, zone, then region) are preferred.
+    pub lb_locality_preference: bool,
+    /// How outbound connections are handled from sources missing from the workload store.
+    pub unknown_source_policy: UnknownSourcePolicy,
+    /// The namespace and service account ztunnel runs as, which make up the identity lent to unknown
+    /// sources by the node_identity policy.
+    pub proxy_namespace: String,
+    pub proxy_service_account: String,
+
     pub termination_grace_period: time::Duration,
+
+    /// How long to wait for a TCP connection to an upstream to be established.
+    pub connect_timeout: Duration,
+    /// How many times a failed outbound connection to a VIP is retried against other endpoints.
+    pub connect_retries: usize,
+    /// The maximum number of concurrent outbound connections, if limited.
+    pub max_connections: Option<usize>,
+    /// The maximum number of concurrent outbound connections from a single source workload, if limited.
+    pub max_connections_per_source: Option<usize>,
+    /// The maximum rate of new outbound connections per second from a single source workload, if limited.
+    pub max_connection_rate_per_source: Option<u32>,
+
+    /// How long a proxied connection may go without any bytes flowing before it is closed.
+    pub idle_timeout: Duration,
+    /// TCP keepalive applied to proxied sockets, if set.
+    pub keepalive: Option<socket::Keepalive>,
+    /// How traffic is captured into our listeners, which determines how original destinations are
+    /// found and, with TPROXY, that upstream connections are made from the client's address.
+    pub capture_mode: socket::CaptureMode,
+    /// The SO_MARK set on every upstream socket, so redirection rules can exempt our own traffic.
+    pub socket_mark: Option<u32>,
+
+    /// Access logs for proxied connections, if enabled. These are independent of the log level.
+    pub access_log: Option<AccessLogConfig>,
+
+    /// The OTLP/HTTP endpoint spans of proxied connections are exported to, such as
+    /// http://collector:4318/v1/traces. If unset, tracing is disabled.
+    pub otlp_endpoint: Option<hyper::Uri>,
+    /// How long a request exporting spans to the OTLP endpoint may take before it is abandoned.
+    pub otlp_timeout: Duration,
+    /// The ratio of new traces which are sampled, from 0 to 1. Traces continued from a client
+    /// follow the client's decision.
+    pub trace_sampling: f64,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
-            tls: std::env::var("TLS").unwrap_or_else(|_| "".into()) != "off",
+            tls: true,
+
+            cluster_id: "Kubernetes".to_string(),
+            cluster_domain: "cluster.local".to_string(),
             window_size: 4 * 1024 * 1024,
             connection_window_size: 4 * 1024 * 1024,
             frame_size: 1024 * 1024,
+            http2_adaptive_window: false,
+            http2_keepalive_interval: Some(Duration::from_secs(10)),
+            http2_keepalive_timeout: Duration::from_secs(20),
+            http2_max_concurrent_streams: 100,
+            http2_max_header_size: 16 * 1024,
+
+            splice: true,
+
+            pool_max_streams_per_conn: 100,
+            pool_idle_timeout: Duration::from_secs(60),
 
             termination_grace_period: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(10),
+            connect_retries: 2,
+            max_connections: None,
+            max_connections_per_source: None,
+            max_connection_rate_per_source: None,
+
+            idle_timeout: Duration::from_secs(60 * 60),
+            keepalive: Some(DEFAULT_KEEPALIVE),
+            capture_mode: Default::default(),
+            socket_mark: None,
+
+            access_log: None,
+
+            otlp_endpoint: None,
+            otlp_timeout: Duration::from_secs(10),
+            trace_sampling: 0.01,
 
             inbound_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15008),
             inbound_plaintext_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15006),
             outbound_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15001),
+            dns_proxy_addr: None,
+            dns_upstreams: vec![],
+            socks5_addr: None,
+            admin_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15021),
+
+            local_node: None,
+            inbound_allowed_addresses: vec![],
 
-            local_node: Some(std::env::var("NODE_NAME").unwrap_or_else(|_| "".into()))
-                .filter(|s| !s.is_empty()),
+            local_xds_path: None,
+            xds_on_demand: false,
 
-            local_xds_path: Some(std::env::var("LOCAL_XDS_PATH").unwrap_or_else(|_| "".into()))
-                .filter(|s| !s.is_empty()),
-            xds_on_demand: std::env::var("XDS_ON_DEMAND").unwrap_or_else(|_| "".into()) == "on",
+            auth: identity::AuthSource::Token(PathBuf::from(DEFAULT_TOKEN_PATH)),
 
-            auth: identity::AuthSource::Token(PathBuf::from(
-                r"./var/run/secrets/tokens/istio-token",
-            )),
+            lb_strategy: Default::default(),
+            lb_locality_preference: false,
+            unknown_source_policy: Default::default(),
+            proxy_namespace: "istio-system".to_string(),
+            proxy_service_account: "ztunnel".to_string(),
         }
     }
 }
+
+const DEFAULT_TOKEN_PATH: &str = "./var/run/secrets/tokens/istio-token";
+const DEFAULT_KEEPALIVE: socket::Keepalive = socket::Keepalive {
+    time: Duration::from_secs(180),
+    interval: Duration::from_secs(20),
+    retries: 6,
+};
+/// The ports listened on when the DNS proxy or SOCKS5 listener is simply turned on.
+const DEFAULT_DNS_PROXY_PORT: u16 = 15053;
+const DEFAULT_SOCKS5_PORT: u16 = 15080;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read config file {0}: {1}")]
+    ReadFile(PathBuf, std::io::Error),
+
+    #[error("failed to parse config file {0}: {1}")]
+    ParseFile(PathBuf, serde_yaml::Error),
+
+    #[error("unknown key {0} in config file")]
+    UnknownKey(String),
+
+    #[error("unknown flag {0}")]
+    UnknownFlag(String),
+
+    #[error("flag {0} requires a value")]
+    MissingValue(String),
+
+    #[error("invalid value {value:?} for {key} from {origin}: {reason}")]
+    InvalidValue {
+        key: &'static str,
+        value: String,
+        origin: String,
+        reason: String,
+    },
+
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+impl Config {
+    /// load builds the configuration from the command-line arguments, excluding the program name,
+    /// and the environment of the process. The result is validated.
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Config, Error> {
+        Self::from_sources(args, |name| std::env::var(name).ok())
+    }
+
+    fn from_sources(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, Error> {
+        // Empty environment variables are treated as unset.
+        let env = |name: &str| env(name).filter(|v| !v.is_empty());
+        let flags = parse_flags(args)?;
+
+        // Later sources take precedence, so each overwrites the values of the ones before it.
+        let mut values: HashMap<&'static str, (String, String)> = HashMap::new();
+        let file = flags
+            .iter()
+            .rev()
+            .find(|(key, _)| *key == CONFIG_FILE_KEY)
+            .map(|(_, path)| path.clone())
+            .or_else(|| env(CONFIG_FILE_ENV));
+        if let Some(path) = file {
+            let path = PathBuf::from(path);
+            for (key, value) in read_file(&path)? {
+                let origin = format!("config file {}", path.display());
+                values.insert(key, (value, origin));
+            }
+        }
+        for setting in SETTINGS {
+            if let Some(value) = env(setting.env) {
+                let origin = format!("environment variable {}", setting.env);
+                values.insert(setting.key, (value, origin));
+            }
+        }
+        for (key, value) in flags {
+            if key != CONFIG_FILE_KEY {
+                let origin = format!("flag {}", flag_name(key));
+                values.insert(key, (value, origin));
+            }
+        }
+
+        // Settings are applied in a fixed order, as some refine others, such as the path of the
+        // access log.
+        let mut cfg = Config::default();
+        for setting in SETTINGS {
+            if let Some((value, origin)) = values.remove(setting.key) {
+                (setting.set)(&mut cfg, &value).map_err(|reason| Error::InvalidValue {
+                    key: setting.key,
+                    value,
+                    origin,
+                    reason,
+                })?;
+            }
+        }
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// validate checks the configuration is consistent, and within the limits of the protocols used.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| -> Result<(), Error> { Err(Error::Invalid(msg)) };
+        // Limits of SETTINGS_MAX_FRAME_SIZE and SETTINGS_INITIAL_WINDOW_SIZE in RFC 9113.
+        if !(16 * 1024..=16 * 1024 * 1024 - 1).contains(&self.frame_size) {
+            return invalid(format!(
+                "frame_size {} must be between 16384 and 16777215",
+                self.frame_size
+            ));
+        }
+        for (key, size) in [
+            ("window_size", self.window_size),
+            ("connection_window_size", self.connection_window_size),
+        ] {
+            if size > i32::MAX as u32 {
+                return invalid(format!("{key} {size} must be at most {}", i32::MAX));
+            }
+        }
+        for (key, value) in [
+            (
+                "http2_max_concurrent_streams",
+                self.http2_max_concurrent_streams as usize,
+            ),
+            ("pool_max_streams_per_conn", self.pool_max_streams_per_conn),
+            ("max_connections", self.max_connections.unwrap_or(1)),
+            (
+                "max_connections_per_source",
+                self.max_connections_per_source.unwrap_or(1),
+            ),
+            (
+                "max_connection_rate_per_source",
+                self.max_connection_rate_per_source.unwrap_or(1) as usize,
+            ),
+        ] {
+            if value == 0 {
+                return invalid(format!("{key} must be at least 1"));
+            }
+        }
+        // A zero timeout would fire at once, failing every connection or ping it applies to.
+        for (key, timeout) in [
+            ("connect_timeout", self.connect_timeout),
+            ("idle_timeout", self.idle_timeout),
+            ("pool_idle_timeout", self.pool_idle_timeout),
+            ("http2_keepalive_timeout", self.http2_keepalive_timeout),
+            ("otlp_timeout", self.otlp_timeout),
+        ] {
+            if timeout.is_zero() {
+                return invalid(format!("{key} must be greater than zero"));
+            }
+        }
+        if let Some(keepalive) = &self.keepalive {
+            // TCP keepalive is configured in whole seconds.
+            if keepalive.time.as_secs() == 0 || keepalive.interval.as_secs() == 0 {
+                return invalid(
+                    "keepalive_time and keepalive_interval must be at least 1s".to_string(),
+                );
+            }
+            if keepalive.time.subsec_nanos() != 0 || keepalive.interval.subsec_nanos() != 0 {
+                return invalid(
+                    "keepalive_time and keepalive_interval must be whole seconds".to_string(),
+                );
+            }
+            if keepalive.retries == 0 {
+                return invalid("keepalive_retries must be at least 1".to_string());
+            }
+        }
+        if cfg!(not(target_os = "linux")) && self.socket_mark.is_some() {
+            return invalid("socket_mark is only supported on Linux".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.trace_sampling) {
+            return invalid(format!(
+                "trace_sampling {} must be between 0 and 1",
+                self.trace_sampling
+            ));
+        }
+        if let Some(AccessLogConfig {
+            format: AccessLogFormat::Text(template),
+            ..
+        }) = &self.access_log
+        {
+            if template.as_str().is_empty() {
+                return invalid("access_log_format must not be empty".to_string());
+            }
+        }
+        if self.unknown_source_policy == UnknownSourcePolicy::NodeIdentity
+            && (self.proxy_namespace.is_empty() || self.proxy_service_account.is_empty())
+        {
+            return invalid(
+                "the node_identity unknown source policy requires proxy_namespace and \
+                 proxy_service_account"
+                    .to_string(),
+            );
+        }
+        // The DNS proxy listens on UDP as well, but shares TCP ports with the other listeners.
+        let mut listeners = vec![
+            ("inbound_addr", self.inbound_addr),
+            ("inbound_plaintext_addr", self.inbound_plaintext_addr),
+            ("outbound_addr", self.outbound_addr),
+            ("admin_addr", self.admin_addr),
+        ];
+        listeners.extend(self.dns_proxy_addr.map(|addr| ("dns_proxy_addr", addr)));
+        listeners.extend(self.socks5_addr.map(|addr| ("socks5_addr", addr)));
+        for (i, (key, addr)) in listeners.iter().enumerate() {
+            // Port 0 binds any free port, so never conflicts.
+            if addr.port() == 0 {
+                continue;
+            }
+            if let Some((other, _)) = listeners[..i].iter().find(|(_, a)| a == addr) {
+                return invalid(format!("{key} and {other} are both {addr}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// usage describes the command-line flags and the environment variable of each setting.
+pub fn usage() -> String {
+    let mut out = String::from(
+        "Usage: ztunnel [--config <file>] [--<setting> <value>]...\n\n\
+         Settings are read from, in increasing order of precedence: defaults, the YAML config file, \
+         environment variables and flags.\n\n",
+    );
+    let _ = writeln!(
+        out,
+        "  {}\n      [env {}] A YAML file mapping setting keys, such as connect_timeout, to values.",
+        flag_name(CONFIG_FILE_KEY),
+        CONFIG_FILE_ENV
+    );
+    for setting in SETTINGS {
+        let _ = writeln!(
+            out,
+            "  {}\n      [env {}] {}",
+            flag_name(setting.key),
+            setting.env,
+            setting.help
+        );
+    }
+    out
+}
+
+const CONFIG_FILE_KEY: &str = "config";
+const CONFIG_FILE_ENV: &str = "CONFIG_FILE";
+
+/// Setting is a single configurable value, which may come from any source.
+struct Setting {
+    /// The key of the setting in the config file, from which its flag is derived.
+    key: &'static str,
+    env: &'static str,
+    help: &'static str,
+    set: fn(&mut Config, &str) -> Result<(), String>,
+}
+
+fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+/// parse_flags returns the settings given as --flag value or --flag=value, in order.
+fn parse_flags(
+    args: impl IntoIterator<Item = String>,
+) -> Result<Vec<(&'static str, String)>, Error> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let key = name
+            .strip_prefix("--")
+            .map(|k| k.replace('-', "_"))
+            .and_then(|k| {
+                std::iter::once(CONFIG_FILE_KEY)
+                    .chain(SETTINGS.iter().map(|s| s.key))
+                    .find(|key| *key == k)
+            })
+            .ok_or_else(|| Error::UnknownFlag(name.clone()))?;
+        let value = value
+            .or_else(|| args.next())
+            .ok_or(Error::MissingValue(name))?;
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+/// read_file returns the settings in a config file. Lists may be given as YAML sequences.
+fn read_file(path: &Path) -> Result<Vec<(&'static str, String)>, Error> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| Error::ReadFile(path.to_path_buf(), e))?;
+    let file: HashMap<String, serde_yaml::Value> =
+        match serde_yaml::from_str::<serde_yaml::Value>(&contents) {
+            // An empty file has no settings.
+            Ok(serde_yaml::Value::Null) => return Ok(vec![]),
+            Ok(value) => serde_yaml::from_value(value),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| Error::ParseFile(path.to_path_buf(), e))?;
+    let mut values = Vec::new();
+    for (key, value) in file {
+        let setting = SETTINGS
+            .iter()
+            .find(|s| s.key == key)
+            .ok_or(Error::UnknownKey(key))?;
+        let value = match value {
+            serde_yaml::Value::Sequence(items) => {
+                items.iter().map(yaml_scalar).collect::<Vec<_>>().join(",")
+            }
+            value => yaml_scalar(&value),
+        };
+        values.push((setting.key, value));
+    }
+    Ok(values)
+}
+
+fn yaml_scalar(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::Null => String::new(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::String(s) => s.clone(),
+        // Anything else is rejected by the parser of the setting.
+        value => serde_yaml::to_string(value).unwrap_or_default(),
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value.trim().parse().map_err(|e: T::Err| e.to_string())
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.trim() {
+        "true" | "on" => Ok(true),
+        "false" | "off" => Ok(false),
+        _ => Err("expected true, false, on or off".to_string()),
+    }
+}
+
+/// parse_duration parses a duration such as 500ms, 10s, 5m or 1h.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (n, unit) = value.split_at(split);
+    let n: u64 = n
+        .parse()
+        .map_err(|_| "expected a duration such as 10s".to_string())?;
+    let secs = |factor: u64| {
+        n.checked_mul(factor)
+            .map(Duration::from_secs)
+            .ok_or_else(|| "duration is too long".to_string())
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => secs(60),
+        "h" => secs(60 * 60),
+        _ => Err("expected a duration such as 10s, with a unit of ms, s, m or h".to_string()),
+    }
+}
+
+/// parse_optional parses an optional setting, which is unset by off or an empty value.
+fn parse_optional<T>(
+    value: &str,
+    f: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    match value.trim() {
+        "" | "off" => Ok(None),
+        value => f(value).map(Some),
+    }
+}
+
+fn parse_list<T: FromStr>(value: &str) -> Result<Vec<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(parse)
+        .collect()
+}
+
+/// parse_listener parses the address of an optional listener. on enables it at its default address.
+fn parse_listener(
+    value: &str,
+    default_ip: IpAddr,
+    default_port: u16,
+) -> Result<Option<SocketAddr>, String> {
+    match value.trim() {
+        "on" => Ok(Some(SocketAddr::new(default_ip, default_port))),
+        value => parse_optional(value, parse),
+    }
+}
+
+fn assign<T>(field: &mut T, value: Result<T, String>) -> Result<(), String> {
+    *field = value?;
+    Ok(())
+}
+
+fn keepalive(cfg: &mut Config) -> &mut socket::Keepalive {
+    cfg.keepalive.get_or_insert(DEFAULT_KEEPALIVE)
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "tls",
+        env: "TLS",
+        help: "Whether HBONE uses mTLS.",
+        set: |c, v| assign(&mut c.tls, parse_bool(v)),
+    },
+    Setting {
+        key: "cluster_id",
+        env: "CLUSTER_ID",
+        help: "The cluster this ztunnel runs in, as shared with peers.",
+        set: |c, v| assign(&mut c.cluster_id, Ok(v.to_string())),
+    },
+    Setting {
+        key: "cluster_domain",
+        env: "CLUSTER_DOMAIN",
+        help: "The DNS domain of the cluster, under which the DNS proxy answers for workloads.",
+        set: |c, v| {
+            assign(
+                &mut c.cluster_domain,
+                Ok(v.trim_end_matches('.').to_string()),
+            )
+        },
+    },
+    Setting {
+        key: "window_size",
+        env: "WINDOW_SIZE",
+        help: "The initial HTTP/2 stream window size, in bytes.",
+        set: |c, v| assign(&mut c.window_size, parse(v)),
+    },
+    Setting {
+        key: "connection_window_size",
+        env: "CONNECTION_WINDOW_SIZE",
+        help: "The initial HTTP/2 connection window size, in bytes.",
+        set: |c, v| assign(&mut c.connection_window_size, parse(v)),
+    },
+    Setting {
+        key: "frame_size",
+        env: "FRAME_SIZE",
+        help: "The maximum HTTP/2 frame size, in bytes.",
+        set: |c, v| assign(&mut c.frame_size, parse(v)),
+    },
+    Setting {
+        key: "http2_adaptive_window",
+        env: "HTTP2_ADAPTIVE_WINDOW",
+        help: "Whether HTTP/2 windows grow with the bandwidth-delay product.",
+        set: |c, v| assign(&mut c.http2_adaptive_window, parse_bool(v)),
+    },
+    Setting {
+        key: "http2_keepalive_interval",
+        env: "HTTP2_KEEPALIVE_INTERVAL",
+        help: "How often idle HBONE connections are pinged, or off.",
+        set: |c, v| {
+            assign(
+                &mut c.http2_keepalive_interval,
+                parse_optional(v, parse_duration),
+            )
+        },
+    },
+    Setting {
+        key: "http2_keepalive_timeout",
+        env: "HTTP2_KEEPALIVE_TIMEOUT",
+        help: "How long to wait for a ping to be acknowledged.",
+        set: |c, v| assign(&mut c.http2_keepalive_timeout, parse_duration(v)),
+    },
+    Setting {
+        key: "http2_max_concurrent_streams",
+        env: "HTTP2_MAX_CONCURRENT_STREAMS",
+        help: "The maximum number of streams on an inbound HBONE connection.",
+        set: |c, v| assign(&mut c.http2_max_concurrent_streams, parse(v)),
+    },
+    Setting {
+        key: "http2_max_header_size",
+        env: "HTTP2_MAX_HEADER_SIZE",
+        help: "The maximum size of HBONE headers, in bytes.",
+        set: |c, v| assign(&mut c.http2_max_header_size, parse(v)),
+    },
+    Setting {
+        key: "splice",
+        env: "SPLICE",
+        help: "Whether plain TCP is proxied with splice(2) where supported.",
+        set: |c, v| assign(&mut c.splice, parse_bool(v)),
+    },
+    Setting {
+        key: "pool_max_streams_per_conn",
+        env: "POOL_MAX_STREAMS_PER_CONN",
+        help: "The maximum number of streams on a pooled HBONE connection.",
+        set: |c, v| assign(&mut c.pool_max_streams_per_conn, parse(v)),
+    },
+    Setting {
+        key: "pool_idle_timeout",
+        env: "POOL_IDLE_TIMEOUT",
+        help: "How long an unused pooled connection is kept.",
+        set: |c, v| assign(&mut c.pool_idle_timeout, parse_duration(v)),
+    },
+    Setting {
+        key: "inbound_addr",
+        env: "INBOUND_ADDR",
+        help: "The address of the inbound HBONE listener.",
+        set: |c, v| assign(&mut c.inbound_addr, parse(v)),
+    },
+    Setting {
+        key: "inbound_plaintext_addr",
+        env: "INBOUND_PLAINTEXT_ADDR",
+        help: "The address of the inbound plaintext listener.",
+        set: |c, v| assign(&mut c.inbound_plaintext_addr, parse(v)),
+    },
+    Setting {
+        key: "outbound_addr",
+        env: "OUTBOUND_ADDR",
+        help: "The address of the outbound listener.",
+        set: |c, v| assign(&mut c.outbound_addr, parse(v)),
+    },
+    Setting {
+        key: "dns_proxy_addr",
+        env: "DNS_PROXY",
+        help: "The address of the DNS proxy, on for the default of [::]:15053, or off.",
+        set: |c, v| {
+            assign(
+                &mut c.dns_proxy_addr,
+                parse_listener(v, IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEFAULT_DNS_PROXY_PORT),
+            )
+        },
+    },
+    Setting {
+        key: "dns_upstreams",
+        env: "DNS_UPSTREAMS",
+        help: "Comma-separated resolvers for queries the DNS proxy cannot answer.",
+        set: |c, v| assign(&mut c.dns_upstreams, parse_list(v)),
+    },
+    Setting {
+        key: "socks5_addr",
+        env: "SOCKS5",
+        help: "The address of the SOCKS5 listener, on for the default of 127.0.0.1:15080, or off.",
+        set: |c, v| {
+            assign(
+                &mut c.socks5_addr,
+                parse_listener(v, IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_SOCKS5_PORT),
+            )
+        },
+    },
+    Setting {
+        key: "admin_addr",
+        env: "ADMIN_ADDR",
+        help: "The address of the admin server.",
+        set: |c, v| assign(&mut c.admin_addr, parse(v)),
+    },
+    Setting {
+        key: "local_node",
+        env: "NODE_NAME",
+        help: "The name of the node this ztunnel runs on.",
+        set: |c, v| assign(&mut c.local_node, parse_optional(v, |v| Ok(v.to_string()))),
+    },
+    Setting {
+        key: "inbound_allowed_addresses",
+        env: "INBOUND_ALLOWED_ADDRESSES",
+        help: "Comma-separated addresses, besides local workloads, inbound HBONE may connect to.",
+        set: |c, v| assign(&mut c.inbound_allowed_addresses, parse_list(v)),
+    },
+    Setting {
+        key: "local_xds_path",
+        env: "LOCAL_XDS_PATH",
+        help: "A YAML file of workloads, used instead of XDS.",
+        set: |c, v| {
+            assign(
+                &mut c.local_xds_path,
+                parse_optional(v, |v| Ok(v.to_string())),
+            )
+        },
+    },
+    Setting {
+        key: "xds_on_demand",
+        env: "XDS_ON_DEMAND",
+        help: "Whether workloads are requested from XDS on demand.",
+        set: |c, v| assign(&mut c.xds_on_demand, parse_bool(v)),
+    },
+    Setting {
+        key: "auth_token_path",
+        env: "AUTH_TOKEN_PATH",
+        help: "The token authenticating ztunnel to the CA.",
+        set: |c, v| {
+            assign(
+                &mut c.auth,
+                Ok(identity::AuthSource::Token(PathBuf::from(v))),
+            )
+        },
+    },
+    Setting {
+        key: "lb_strategy",
+        env: "LB_STRATEGY",
+        help: "random, round_robin, least_connections or power_of_two_choices.",
+        set: |c, v| assign(&mut c.lb_strategy, parse(v)),
+    },
+    Setting {
+        key: "lb_locality_preference",
+        env: "LB_LOCALITY_PREFERENCE",
+        help: "Whether endpoints closest to the source are preferred.",
+        set: |c, v| assign(&mut c.lb_locality_preference, parse_bool(v)),
+    },
+    Setting {
+        key: "unknown_source_policy",
+        env: "UNKNOWN_SOURCE_POLICY",
+        help: "reject, passthrough or node_identity, for sources missing from the workload store.",
+        set: |c, v| assign(&mut c.unknown_source_policy, parse(v)),
+    },
+    Setting {
+        key: "proxy_namespace",
+        env: "POD_NAMESPACE",
+        help: "The namespace ztunnel runs in.",
+        set: |c, v| assign(&mut c.proxy_namespace, Ok(v.to_string())),
+    },
+    Setting {
+        key: "proxy_service_account",
+        env: "SERVICE_ACCOUNT",
+        help: "The service account ztunnel runs as.",
+        set: |c, v| assign(&mut c.proxy_service_account, Ok(v.to_string())),
+    },
+    Setting {
+        key: "termination_grace_period",
+        env: "TERMINATION_GRACE_PERIOD",
+        help: "How long open connections may take to complete on shutdown.",
+        set: |c, v| assign(&mut c.termination_grace_period, parse_duration(v)),
+    },
+    Setting {
+        key: "connect_timeout",
+        env: "CONNECT_TIMEOUT",
+        help: "How long to wait for an upstream connection.",
+        set: |c, v| assign(&mut c.connect_timeout, parse_duration(v)),
+    },
+    Setting {
+        key: "connect_retries",
+        env: "CONNECT_RETRIES",
+        help: "How many times a failed connection to a VIP is retried.",
+        set: |c, v| assign(&mut c.connect_retries, parse(v)),
+    },
+    Setting {
+        key: "max_connections",
+        env: "MAX_CONNECTIONS",
+        help: "The maximum number of outbound connections, or off.",
+        set: |c, v| assign(&mut c.max_connections, parse_optional(v, parse)),
+    },
+    Setting {
+        key: "max_connections_per_source",
+        env: "MAX_CONNECTIONS_PER_SOURCE",
+        help: "The maximum number of outbound connections per source, or off.",
+        set: |c, v| assign(&mut c.max_connections_per_source, parse_optional(v, parse)),
+    },
+    Setting {
+        key: "max_connection_rate_per_source",
+        env: "MAX_CONNECTION_RATE_PER_SOURCE",
+        help: "The maximum rate of new outbound connections per second per source, or off.",
+        set: |c, v| {
+            assign(
+                &mut c.max_connection_rate_per_source,
+                parse_optional(v, parse),
+            )
+        },
+    },
+    Setting {
+        key: "idle_timeout",
+        env: "IDLE_TIMEOUT",
+        help: "How long a connection may go without traffic before it is closed.",
+        set: |c, v| assign(&mut c.idle_timeout, parse_duration(v)),
+    },
+    Setting {
+        key: "keepalive_time",
+        env: "KEEPALIVE_TIME",
+        help: "How long a connection is idle before keepalive probes, in whole seconds, or off.",
+        set: |c, v| {
+            let time = parse_optional(v, parse_duration)?;
+            let current = c.keepalive.unwrap_or(DEFAULT_KEEPALIVE);
+            c.keepalive = time.map(|time| socket::Keepalive { time, ..current });
+            Ok(())
+        },
+    },
+    Setting {
+        key: "keepalive_interval",
+        env: "KEEPALIVE_INTERVAL",
+        help: "The interval between TCP keepalive probes, in whole seconds.",
+        set: |c, v| assign(&mut keepalive(c).interval, parse_duration(v)),
+    },
+    Setting {
+        key: "keepalive_retries",
+        env: "KEEPALIVE_RETRIES",
+        help: "How many unanswered TCP keepalive probes close a connection.",
+        set: |c, v| assign(&mut keepalive(c).retries, parse(v)),
+    },
+    Setting {
+        key: "capture_mode",
+        env: "CAPTURE_MODE",
+        help: "redirect, tproxy or auto, as traffic is captured.",
+        set: |c, v| assign(&mut c.capture_mode, parse(v)),
+    },
+    Setting {
+        key: "socket_mark",
+        env: "SOCKET_MARK",
+        help: "The SO_MARK set on upstream sockets, or off.",
+        set: |c, v| assign(&mut c.socket_mark, parse_optional(v, parse)),
+    },
+    Setting {
+        key: "access_log",
+        env: "ACCESS_LOG",
+        help: "json, text or off.",
+        set: |c, v| {
+            let format = match v.trim() {
+                "json" => Some(AccessLogFormat::Json),
+                "text" => Some(AccessLogFormat::Text(AccessLogTemplate::new(
+                    DEFAULT_ACCESS_LOG_FORMAT,
+                ))),
+                "off" => None,
+                _ => return Err("expected json, text or off".to_string()),
+            };
+            c.access_log = format.map(|format| AccessLogConfig { format, path: None });
+            Ok(())
+        },
+    },
+    Setting {
+        key: "access_log_format",
+        env: "ACCESS_LOG_FORMAT",
+        help: "The template of text access logs, in which %FIELD% is replaced by each field.",
+        set: |c, v| match &mut c.access_log {
+            Some(AccessLogConfig {
+                format: AccessLogFormat::Text(template),
+                ..
+            }) => {
+                *template = AccessLogTemplate::new(v);
+                Ok(())
+            }
+            Some(_) => Err("only applies to text access logs".to_string()),
+            None => Err("requires access_log to be enabled".to_string()),
+        },
+    },
+    Setting {
+        key: "access_log_path",
+        env: "ACCESS_LOG_PATH",
+        help: "The file access logs are appended to, rather than stdout.",
+        set: |c, v| {
+            let path = parse_optional(v, |v| Ok(PathBuf::from(v)))?;
+            match &mut c.access_log {
+                Some(access_log) => {
+                    access_log.path = path;
+                    Ok(())
+                }
+                None if path.is_none() => Ok(()),
+                None => Err("requires access_log to be enabled".to_string()),
+            }
+        },
+    },
+    Setting {
+        key: "otlp_endpoint",
+        env: "OTLP_ENDPOINT",
+        help: "The OTLP/HTTP endpoint spans are exported to, or off.",
+        set: |c, v| assign(&mut c.otlp_endpoint, parse_optional(v, parse)),
+    },
+    Setting {
+        key: "otlp_timeout",
+        env: "OTLP_TIMEOUT",
+        help: "How long a request exporting spans may take.",
+        set: |c, v| assign(&mut c.otlp_timeout, parse_duration(v)),
+    },
+    Setting {
+        key: "trace_sampling",
+        env: "TRACE_SAMPLING",
+        help: "The ratio of new traces which are sampled, from 0 to 1.",
+        set: |c, v| assign(&mut c.trace_sampling, parse(v)),
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn precedence() {
+        let path = std::env::temp_dir().join(format!("ztunnel-config-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "connect_timeout: 1s\nconnect_retries: 5\nlocal_node: file-node\n\
+             inbound_allowed_addresses: [10.0.0.1, 10.0.0.2]\naccess_log: text\n",
+        )
+        .unwrap();
+        let env = HashMap::from([
+            ("CONNECT_RETRIES", "6"),
+            ("NODE_NAME", "env-node"),
+            ("ACCESS_LOG_PATH", "/tmp/access.log"),
+            ("SPLICE", ""),
+            ("SOCKS5", "on"),
+        ]);
+        let cfg = Config::from_sources(
+            args(&[
+                "--config",
+                path.to_str().unwrap(),
+                "--local-node=flag-node",
+                "--admin-addr",
+                "127.0.0.1:9000",
+            ]),
+            |name| env.get(name).map(|v| v.to_string()),
+        );
+        std::fs::remove_file(&path).unwrap();
+        let cfg = cfg.unwrap();
+
+        assert_eq!(cfg.connect_timeout, Duration::from_secs(1), "file");
+        assert_eq!(
+            cfg.inbound_allowed_addresses,
+            vec![
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))
+            ],
+            "file list"
+        );
+        assert_eq!(cfg.connect_retries, 6, "env over file");
+        assert_eq!(
+            cfg.local_node.as_deref(),
+            Some("flag-node"),
+            "flag over env"
+        );
+        assert_eq!(cfg.admin_addr, "127.0.0.1:9000".parse().unwrap(), "flag");
+        assert!(cfg.splice, "empty env is unset");
+        assert_eq!(
+            cfg.socks5_addr,
+            Some("127.0.0.1:15080".parse().unwrap()),
+            "socks5 on loopback"
+        );
+        let access_log = cfg.access_log.unwrap();
+        assert_eq!(
+            access_log.format,
+            AccessLogFormat::Text(AccessLogTemplate::new(DEFAULT_ACCESS_LOG_FORMAT))
+        );
+        assert_eq!(access_log.path, Some(PathBuf::from("/tmp/access.log")));
+    }
+
+    #[test]
+    fn invalid() {
+        let load = |flags: &[&str], env: &[(&str, &str)]| {
+            let env: HashMap<_, _> = env.iter().copied().collect();
+            Config::from_sources(args(flags), |name| env.get(name).map(|v| v.to_string()))
+        };
+        assert!(load(&[], &[]).is_ok());
+
+        let err = load(&[], &[("TLS", "maybe")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value \"maybe\" for tls from environment variable TLS: \
+             expected true, false, on or off"
+        );
+        let err = load(&["--connect-timeout", "10"], &[]).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidValue {
+                key: "connect_timeout",
+                ..
+            }
+        ));
+        assert!(matches!(
+            load(&["--unknown", "1"], &[]).unwrap_err(),
+            Error::UnknownFlag(_)
+        ));
+        assert!(matches!(
+            load(&["--connect-retries"], &[]).unwrap_err(),
+            Error::MissingValue(_)
+        ));
+
+        // Values that parse, but are inconsistent.
+        let err = load(&["--trace-sampling", "2"], &[]).unwrap_err();
+        assert!(matches!(err, Error::Invalid(_)), "{err}");
+        let err = load(&["--admin-addr", "[::]:15001"], &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid configuration: admin_addr and outbound_addr are both [::]:15001"
+        );
+        let err = load(&["--frame-size", "1024"], &[]).unwrap_err();
+        assert!(matches!(err, Error::Invalid(_)), "{err}");
+        let err = load(&["--keepalive-interval", "1500ms"], &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid configuration: keepalive_time and keepalive_interval must be whole seconds"
+        );
+        let err = load(&[], &[("IDLE_TIMEOUT", "0s")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid configuration: idle_timeout must be greater than zero"
+        );
+
+        // Access log refinements must have an access log to apply to.
+        let err = load(&["--access-log-path", "/tmp/access.log"], &[]).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidValue {
+                key: "access_log_path",
+                ..
+            }
+        ));
+        let err = load(
+            &["--access-log=json", "--access-log-format", "%START_TIME%"],
+            &[],
+        );
+        assert!(matches!(
+            err.unwrap_err(),
+            Error::InvalidValue {
+                key: "access_log_format",
+                ..
+            }
+        ));
+        assert!(load(
+            &["--access-log=text", "--access-log-format", "%START_TIME%"],
+            &[]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX)).is_err());
+    }
+}
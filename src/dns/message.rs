@@ -0,0 +1,169 @@
+use std::net::IpAddr;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+const HEADER_LEN: usize = 12;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+
+pub const RCODE_SERVFAIL: u16 = 2;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum MessageError {
+    #[error("message truncated")]
+    Truncated,
+    #[error("unsupported message: {0}")]
+    Unsupported(&'static str),
+}
+
+/// Query is a parsed DNS query with a single question, the only form we answer ourselves.
+#[derive(Debug)]
+pub struct Query<'a> {
+    pub id: u16,
+    flags: u16,
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    // The raw question section, echoed back in responses.
+    question: &'a [u8],
+}
+
+impl<'a> Query<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Query<'a>, MessageError> {
+        if buf.len() < HEADER_LEN {
+            return Err(MessageError::Truncated);
+        }
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        let flags = u16::from_be_bytes([buf[2], buf[3]]);
+        let qdcount = u16::from_be_bytes([buf[4], buf[5]]);
+        if flags & FLAG_QR != 0 {
+            return Err(MessageError::Unsupported("not a query"));
+        }
+        if flags & OPCODE_MASK != 0 {
+            return Err(MessageError::Unsupported("opcode"));
+        }
+        if qdcount != 1 {
+            return Err(MessageError::Unsupported("question count"));
+        }
+
+        let mut labels = Vec::new();
+        let mut pos = HEADER_LEN;
+        loop {
+            let len = *buf.get(pos).ok_or(MessageError::Truncated)? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            if len & 0xc0 != 0 {
+                return Err(MessageError::Unsupported("compressed question"));
+            }
+            let label = buf.get(pos..pos + len).ok_or(MessageError::Truncated)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += len;
+        }
+        let fixed = buf.get(pos..pos + 4).ok_or(MessageError::Truncated)?;
+        let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+        pos += 4;
+
+        Ok(Query {
+            id,
+            flags,
+            name: labels.join("."),
+            qtype,
+            qclass,
+            question: &buf[HEADER_LEN..pos],
+        })
+    }
+
+    /// answer builds an authoritative response with a record for each address. If the response
+    /// would exceed max_len, the answers are dropped and the response is marked truncated, so the
+    /// client retries over TCP.
+    pub fn answer(&self, addrs: &[IpAddr], ttl: u32, max_len: usize) -> Vec<u8> {
+        let mut answers = Vec::new();
+        for addr in addrs {
+            let (rtype, rdata) = match addr {
+                IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+            };
+            // The name is a pointer to the question.
+            answers.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            answers.extend_from_slice(&rtype.to_be_bytes());
+            answers.extend_from_slice(&CLASS_IN.to_be_bytes());
+            answers.extend_from_slice(&ttl.to_be_bytes());
+            answers.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            answers.extend_from_slice(&rdata);
+        }
+
+        let mut flags = FLAG_QR | FLAG_AA | FLAG_RA | (self.flags & FLAG_RD);
+        let mut ancount = addrs.len() as u16;
+        if HEADER_LEN + self.question.len() + answers.len() > max_len {
+            flags |= FLAG_TC;
+            ancount = 0;
+            answers.clear();
+        }
+        let mut res = Vec::with_capacity(HEADER_LEN + self.question.len() + answers.len());
+        res.extend_from_slice(&self.id.to_be_bytes());
+        res.extend_from_slice(&flags.to_be_bytes());
+        res.extend_from_slice(&1u16.to_be_bytes());
+        res.extend_from_slice(&ancount.to_be_bytes());
+        res.extend_from_slice(&[0, 0, 0, 0]);
+        res.extend_from_slice(self.question);
+        res.extend_from_slice(&answers);
+        res
+    }
+}
+
+/// is_response returns true if response is a response to the raw query: it must have the same ID
+/// and echo the same question section. Anything else is a late reply to an earlier query, or
+/// spoofed.
+pub fn is_response(query: &[u8], response: &[u8]) -> bool {
+    let (Some(len), Some(header)) = (question_len(query), response.get(..HEADER_LEN)) else {
+        return false;
+    };
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    header[..2] == query[..2]
+        && flags & FLAG_QR != 0
+        && header[4..6] == query[4..6]
+        && response.get(HEADER_LEN..HEADER_LEN + len) == query.get(HEADER_LEN..HEADER_LEN + len)
+}
+
+/// question_len returns the length of the question section of a raw message.
+fn question_len(buf: &[u8]) -> Option<usize> {
+    let qdcount = u16::from_be_bytes([*buf.get(4)?, *buf.get(5)?]);
+    let mut pos = HEADER_LEN;
+    for _ in 0..qdcount {
+        loop {
+            let len = *buf.get(pos)? as usize;
+            if len & 0xc0 == 0xc0 {
+                // A pointer ends the name.
+                pos += 2;
+                break;
+            }
+            pos += 1 + len;
+            if len == 0 {
+                break;
+            }
+        }
+        pos += 4;
+    }
+    (pos <= buf.len()).then_some(pos - HEADER_LEN)
+}
+
+/// error_response builds a response to the raw query with the given response code, without
+/// requiring the query to be understood.
+pub fn error_response(query: &[u8], rcode: u16) -> Option<Vec<u8>> {
+    let header = query.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let flags = FLAG_QR | FLAG_RA | (flags & (OPCODE_MASK | FLAG_RD)) | rcode;
+    let mut res = vec![0u8; HEADER_LEN];
+    res[..2].copy_from_slice(&header[..2]);
+    res[2..4].copy_from_slice(&flags.to_be_bytes());
+    Some(res)
+}
@@ -0,0 +1,486 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use drain::Watch;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{debug, error, info, warn};
+
+use crate::socket::Dialer;
+use crate::workload::WorkloadInformation;
+
+mod message;
+
+use message::Query;
+
+/// The TTL of records we answer ourselves. This is kept short, as workloads come and go.
+const TTL: u32 = 30;
+/// The maximum size of a response over UDP, without EDNS.
+const MAX_UDP_LEN: usize = 512;
+/// The maximum size of a message over TCP.
+const MAX_TCP_LEN: usize = u16::MAX as usize;
+/// How long to wait for an upstream resolver to respond.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Server is a DNS proxy. It answers A and AAAA queries for service and workload hostnames known
+/// to the workload store, and forwards all other queries to the upstream resolvers.
+pub struct Server {
+    udp: UdpSocket,
+    tcp: TcpListener,
+    resolver: Arc<Resolver>,
+    drain: Watch,
+}
+
+struct Resolver {
+    workloads: WorkloadInformation,
+    upstreams: Vec<SocketAddr>,
+    // Queries to upstreams are our own traffic, so go through the dialer to be marked as such.
+    dialer: Dialer,
+}
+
+impl Server {
+    /// new binds the DNS server on addr, over both UDP and TCP. If upstreams is empty, the
+    /// nameservers from /etc/resolv.conf are used. Queries are forwarded to them through dialer.
+    pub async fn new(
+        addr: SocketAddr,
+        upstreams: Vec<SocketAddr>,
+        dialer: Dialer,
+        workloads: WorkloadInformation,
+        drain: Watch,
+    ) -> io::Result<Server> {
+        let udp = UdpSocket::bind(addr).await?;
+        // Bind TCP to the same port, in case an ephemeral port was requested.
+        let tcp = TcpListener::bind(udp.local_addr()?).await?;
+        let upstreams = if upstreams.is_empty() {
+            read_resolv_conf(Path::new("/etc/resolv.conf")).await
+        } else {
+            upstreams
+        };
+        info!("dns upstreams: {:?}", upstreams);
+        Ok(Server {
+            udp,
+            tcp,
+            resolver: Arc::new(Resolver {
+                workloads,
+                upstreams,
+                dialer,
+            }),
+            drain,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.udp.local_addr().unwrap()
+    }
+
+    pub async fn run(self) {
+        info!("dns listener established {}", self.local_addr());
+        let udp = Arc::new(self.udp);
+        let resolver = self.resolver;
+
+        let serve_udp = {
+            let resolver = resolver.clone();
+            async move {
+                let mut buf = vec![0u8; MAX_TCP_LEN];
+                loop {
+                    let (n, from) = match udp.recv_from(&mut buf).await {
+                        Ok(res) => res,
+                        Err(e) => {
+                            error!("dns udp receive failed: {}", e);
+                            continue;
+                        }
+                    };
+                    let request = buf[..n].to_vec();
+                    let resolver = resolver.clone();
+                    let udp = udp.clone();
+                    tokio::spawn(async move {
+                        if let Some(response) = resolver.resolve(&request, false).await {
+                            if let Err(e) = udp.send_to(&response, from).await {
+                                warn!("dns udp response to {} failed: {}", from, e);
+                            }
+                        }
+                    });
+                }
+            }
+        };
+
+        let tcp = self.tcp;
+        let serve_tcp = async move {
+            loop {
+                match tcp.accept().await {
+                    Ok((stream, from)) => {
+                        let resolver = resolver.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = resolver.serve_tcp(stream).await {
+                                debug!("dns tcp connection from {} failed: {}", from, e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Failed TCP handshake {}", e),
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = serve_udp => {}
+            _ = serve_tcp => {}
+            _ = self.drain.signaled() => {
+                info!("dns drained");
+            }
+        }
+    }
+}
+
+impl Resolver {
+    async fn serve_tcp(&self, mut stream: TcpStream) -> io::Result<()> {
+        loop {
+            let len = match stream.read_u16().await {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let mut request = vec![0u8; len];
+            stream.read_exact(&mut request).await?;
+            if let Some(response) = self.resolve(&request, true).await {
+                stream.write_u16(response.len() as u16).await?;
+                stream.write_all(&response).await?;
+            }
+        }
+    }
+
+    /// resolve returns the response to a raw DNS query, answering A and AAAA queries from the
+    /// workload store if possible, and otherwise forwarding it upstream.
+    async fn resolve(&self, request: &[u8], tcp: bool) -> Option<Vec<u8>> {
+        match Query::parse(request) {
+            Ok(query)
+                if query.qclass == message::CLASS_IN
+                    && (query.qtype == message::TYPE_A || query.qtype == message::TYPE_AAAA) =>
+            {
+                let addrs = self.workloads.find_hostname(&query.name);
+                if !addrs.is_empty() {
+                    let addrs: Vec<IpAddr> = addrs
+                        .into_iter()
+                        .filter(|addr| addr.is_ipv4() == (query.qtype == message::TYPE_A))
+                        .collect();
+                    debug!("dns answering {} with {:?}", query.name, addrs);
+                    let max_len = if tcp { MAX_TCP_LEN } else { MAX_UDP_LEN };
+                    return Some(query.answer(&addrs, TTL, max_len));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => debug!("dns forwarding query we cannot answer: {}", e),
+        }
+        match self.forward(request, tcp).await {
+            Ok(response) => Some(response),
+            Err(e) => {
+                warn!("dns forwarding failed: {}", e);
+                message::error_response(request, message::RCODE_SERVFAIL)
+            }
+        }
+    }
+
+    /// forward sends the query to each upstream in turn, returning the first response.
+    async fn forward(&self, request: &[u8], tcp: bool) -> io::Result<Vec<u8>> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no upstream resolvers");
+        for upstream in &self.upstreams {
+            let res = if tcp {
+                let forward = forward_tcp(&self.dialer, *upstream, request);
+                tokio::time::timeout(UPSTREAM_TIMEOUT, forward).await
+            } else {
+                let forward = forward_udp(&self.dialer, *upstream, request);
+                tokio::time::timeout(UPSTREAM_TIMEOUT, forward).await
+            };
+            match res {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(e)) => last_err = e,
+                Err(_) => {
+                    last_err = io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("upstream {upstream} timed out"),
+                    )
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// forward_udp sends the query to upstream, returning the first reply that is a response to it.
+/// Other replies are dropped, as anyone able to reach the socket can send them.
+async fn forward_udp(dialer: &Dialer, upstream: SocketAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = dialer.connect_udp(upstream).await?;
+    socket.send(request).await?;
+    let mut buf = vec![0u8; MAX_TCP_LEN];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        if message::is_response(request, &buf[..n]) {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+        debug!("dns dropping mismatched response from {}", upstream);
+    }
+}
+
+async fn forward_tcp(dialer: &Dialer, upstream: SocketAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = dialer.connect(upstream, None).await?;
+    stream.write_u16(request.len() as u16).await?;
+    stream.write_all(request).await?;
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// read_resolv_conf returns the nameservers configured in a resolv.conf file.
+async fn read_resolv_conf(path: &Path) -> Vec<SocketAddr> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to read {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    data.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("nameserver"), Some(ip)) => ip.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use bytes::Bytes;
+
+    use crate::workload::WorkloadStore;
+    use crate::xds::istio::workload::Workload as XdsWorkload;
+
+    use super::*;
+
+    const TYPE_MX: u16 = 15;
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        buf.push(0);
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&message::CLASS_IN.to_be_bytes());
+        buf
+    }
+
+    /// answers parses the addresses out of a response to a query built by query().
+    fn answers(response: &[u8]) -> Vec<IpAddr> {
+        let ancount = u16::from_be_bytes([response[6], response[7]]);
+        let mut pos = 12;
+        while response[pos] != 0 {
+            pos += response[pos] as usize + 1;
+        }
+        pos += 5;
+        let mut addrs = Vec::new();
+        for _ in 0..ancount {
+            let rdlen = u16::from_be_bytes([response[pos + 10], response[pos + 11]]) as usize;
+            let rdata = &response[pos + 12..pos + 12 + rdlen];
+            addrs.push(match rdlen {
+                4 => IpAddr::from(<[u8; 4]>::try_from(rdata).unwrap()),
+                _ => IpAddr::from(<[u8; 16]>::try_from(rdata).unwrap()),
+            });
+            pos += 12 + rdlen;
+        }
+        addrs
+    }
+
+    /// stub_upstream answers every query with 1.2.3.4, over UDP and TCP.
+    async fn stub_upstream() -> SocketAddr {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        let stub = IpAddr::from([1, 2, 3, 4]);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            loop {
+                let (n, from) = udp.recv_from(&mut buf).await.unwrap();
+                let response = Query::parse(&buf[..n]).unwrap().answer(&[stub], 60, 512);
+                udp.send_to(&response, from).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap();
+                let mut buf = vec![0u8; len as usize];
+                stream.read_exact(&mut buf).await.unwrap();
+                let response = Query::parse(&buf).unwrap().answer(&[stub], 60, 512);
+                stream.write_u16(response.len() as u16).await.unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn server(upstream: SocketAddr) -> (SocketAddr, drain::Signal) {
+        let store = WorkloadStore::test_store(vec![XdsWorkload {
+            name: "pod".to_string(),
+            namespace: "ns".to_string(),
+            address: Bytes::copy_from_slice(&[127, 0, 0, 1]),
+            service_hostnames: HashMap::from([(
+                "10.0.0.1".to_string(),
+                "svc.ns.svc.cluster.local".to_string(),
+            )]),
+            ..Default::default()
+        }])
+        .unwrap();
+        let workloads = WorkloadInformation {
+            info: Arc::new(Mutex::new(store)),
+            demand: None,
+        };
+        let (signal, drain) = drain::channel();
+        let server = Server::new(
+            "127.0.0.1:0".parse().unwrap(),
+            vec![upstream],
+            Dialer::default(),
+            workloads,
+            drain,
+        )
+        .await
+        .unwrap();
+        let addr = server.local_addr();
+        tokio::spawn(server.run());
+        (addr, signal)
+    }
+
+    async fn resolve_udp(server: SocketAddr, name: &str, qtype: u16) -> Vec<IpAddr> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        socket.send(&query(7, name, qtype)).await.unwrap();
+        let mut buf = vec![0u8; 512];
+        let n = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..2], &7u16.to_be_bytes(), "id must match");
+        answers(&buf[..n])
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let (server, _signal) = server(stub_upstream().await).await;
+        let vip = IpAddr::from([10, 0, 0, 1]);
+        let stub = IpAddr::from([1, 2, 3, 4]);
+
+        assert_eq!(
+            resolve_udp(server, "svc.ns.svc.cluster.local", message::TYPE_A).await,
+            vec![vip]
+        );
+        assert_eq!(
+            resolve_udp(server, "pod.ns.svc.cluster.local", message::TYPE_A).await,
+            vec![IpAddr::from([127, 0, 0, 1])]
+        );
+        assert_eq!(
+            resolve_udp(server, "pod.ns", message::TYPE_A).await,
+            vec![stub],
+            "workloads are only known under the cluster domain"
+        );
+        assert!(
+            resolve_udp(server, "svc.ns.svc.cluster.local", message::TYPE_AAAA)
+                .await
+                .is_empty(),
+            "known name without IPv6 addresses"
+        );
+        assert_eq!(
+            resolve_udp(server, "svc.ns.svc.cluster.local", TYPE_MX).await,
+            vec![stub],
+            "other types for a known name are forwarded"
+        );
+        assert_eq!(
+            resolve_udp(server, "example.com", message::TYPE_A).await,
+            vec![stub],
+            "forwarded"
+        );
+
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        for (name, expected) in [("svc.ns.svc.cluster.local", vip), ("example.com", stub)] {
+            let request = query(8, name, message::TYPE_A);
+            stream.write_u16(request.len() as u16).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut response = vec![0u8; len as usize];
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(answers(&response), vec![expected], "tcp {name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn mismatched_response() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            let (n, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let query = Query::parse(&buf[..n]).unwrap();
+            // A reply to some other query arrives first, and must not be returned to the client.
+            let mut other = query.answer(&[IpAddr::from([6, 6, 6, 6])], 60, 512);
+            other[1] ^= 0xff;
+            upstream.send_to(&other, from).await.unwrap();
+            let response = query.answer(&[IpAddr::from([1, 2, 3, 4])], 60, 512);
+            upstream.send_to(&response, from).await.unwrap();
+        });
+        let (server, _signal) = server(addr).await;
+        assert_eq!(
+            resolve_udp(server, "example.com", message::TYPE_A).await,
+            vec![IpAddr::from([1, 2, 3, 4])]
+        );
+    }
+
+    #[tokio::test]
+    async fn upstream_failure() {
+        // Bind and immediately drop a socket to find a port nothing is listening on.
+        let unused = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (server, _signal) = server(unused).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        socket
+            .send(&query(9, "example.com", message::TYPE_A))
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 512];
+        let n = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(n, 12, "header only");
+        assert_eq!(buf[3] & 0x0f, message::RCODE_SERVFAIL as u8);
+    }
+
+    #[tokio::test]
+    async fn resolv_conf() {
+        let path = std::env::temp_dir().join(format!("ztunnel-resolv-{}", std::process::id()));
+        tokio::fs::write(
+            &path,
+            "search default.svc.cluster.local\nnameserver 10.96.0.10\nnameserver ::1\n",
+        )
+        .await
+        .unwrap();
+        let upstreams = read_resolv_conf(&path).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(
+            upstreams,
+            vec![
+                "10.96.0.10:53".parse().unwrap(),
+                "[::1]:53".parse().unwrap()
+            ]
+        );
+    }
+}
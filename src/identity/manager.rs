@@ -1,11 +1,12 @@
 use std::fmt;
+use std::str::FromStr;
 use tracing::instrument;
 
 use super::CaClient;
 use super::Error;
 use crate::tls;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Identity {
     Spiffe {
         trust_domain: String,
@@ -29,6 +30,29 @@ impl fmt::Display for Identity {
     }
 }
 
+impl FromStr for Identity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidIdentity(s.to_string());
+        let rest = s.strip_prefix("spiffe://").ok_or_else(invalid)?;
+        match rest.split('/').collect::<Vec<_>>()[..] {
+            [trust_domain, "ns", namespace, "sa", service_account]
+                if !trust_domain.is_empty()
+                    && !namespace.is_empty()
+                    && !service_account.is_empty() =>
+            {
+                Ok(Identity::Spiffe {
+                    trust_domain: trust_domain.to_string(),
+                    namespace: namespace.to_string(),
+                    service_account: service_account.to_string(),
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct SecretManager {
     client: CaClient,
@@ -42,6 +66,39 @@ impl SecretManager {
 
     #[instrument(skip_all, fields(%id))]
     pub async fn fetch_certificate(&self, id: Identity) -> Result<tls::Certs, Error> {
-        self.client.clone().fetch_certificate(id).await
+        let res = self.client.clone().fetch_certificate(id).await;
+        let result = if res.is_ok() { "success" } else { "error" };
+        crate::metrics::CERTIFICATE_FETCHES.inc(vec![("result", result.to_string())]);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_identity() {
+        let id: Identity = "spiffe://cluster.local/ns/default/sa/sleep"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            id,
+            Identity::Spiffe {
+                trust_domain: "cluster.local".to_string(),
+                namespace: "default".to_string(),
+                service_account: "sleep".to_string(),
+            }
+        );
+        assert_eq!(id.to_string(), "spiffe://cluster.local/ns/default/sa/sleep");
+
+        for invalid in [
+            "cluster.local/ns/default/sa/sleep",
+            "spiffe://cluster.local/ns/default",
+            "spiffe://cluster.local/ns//sa/sleep",
+            "spiffe://cluster.local/sa/sleep/ns/default",
+        ] {
+            assert!(invalid.parse::<Identity>().is_err(), "{invalid}");
+        }
     }
 }
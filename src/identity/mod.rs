@@ -17,4 +17,6 @@ pub enum Error {
     SigningRequest(#[from] tonic::Status),
     #[error("failed to process string: {0}")]
     Utf8(#[from] Utf8Error),
+    #[error("invalid identity: {0}")]
+    InvalidIdentity(String),
 }
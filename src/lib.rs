@@ -1,7 +1,9 @@
 pub mod admin;
 pub mod app;
 pub mod config;
+pub mod dns;
 pub mod identity;
+pub mod metrics;
 pub mod proxy;
 pub mod signal;
 pub mod socket;
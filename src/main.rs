@@ -12,9 +12,12 @@ use ztunnel::*;
 
 #[tokio::main(worker_threads = 2)]
 async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", config::usage());
+        return Ok(());
+    }
     telemetry::setup_logging();
-    let config = ztunnel::config::Config {
-        ..Default::default()
-    };
+    let config = config::Config::load(args)?;
     app::spawn(signal::Shutdown::new(), config).await
 }
@@ -0,0 +1,176 @@
+//! Metrics exposed on the admin server's /metrics endpoint, in the Prometheus text format.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+/// Labels are the label names and values identifying a single counter in a Family.
+pub type Labels = Vec<(&'static str, String)>;
+
+pub static TCP_CONNECTIONS_OPENED: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "istio_tcp_connections_opened_total",
+        "The total number of TCP connections opened",
+    )
+});
+pub static TCP_CONNECTIONS_CLOSED: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "istio_tcp_connections_closed_total",
+        "The total number of TCP connections closed",
+    )
+});
+pub static TCP_SENT_BYTES: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "istio_tcp_sent_bytes_total",
+        "The size of total bytes sent during response in case of a TCP connection",
+    )
+});
+pub static TCP_RECEIVED_BYTES: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "istio_tcp_received_bytes_total",
+        "The size of total bytes received during request in case of a TCP connection",
+    )
+});
+pub static XDS_RESPONSES: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "ztunnel_xds_responses_total",
+        "The total number of XDS responses received, by type and whether they were accepted",
+    )
+});
+pub static XDS_CONNECTION_TERMINATIONS: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "ztunnel_xds_connection_terminations_total",
+        "The total number of times the XDS stream ended, by reason",
+    )
+});
+pub static CERTIFICATE_FETCHES: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "ztunnel_certificate_fetches_total",
+        "The total number of workload certificates requested from the CA, by result",
+    )
+});
+pub static LISTENER_ERRORS: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "ztunnel_listener_errors_total",
+        "The total number of connections that failed to be accepted, by listener",
+    )
+});
+
+pub static UNKNOWN_SOURCE_CONNECTIONS: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "ztunnel_unknown_source_connections_total",
+        "The total number of outbound connections from sources missing from the workload store, by the policy applied",
+    )
+});
+
+fn families() -> [&'static Family; 9] {
+    [
+        &TCP_CONNECTIONS_OPENED,
+        &TCP_CONNECTIONS_CLOSED,
+        &TCP_SENT_BYTES,
+        &TCP_RECEIVED_BYTES,
+        &XDS_RESPONSES,
+        &XDS_CONNECTION_TERMINATIONS,
+        &CERTIFICATE_FETCHES,
+        &LISTENER_ERRORS,
+        &UNKNOWN_SOURCE_CONNECTIONS,
+    ]
+}
+
+/// encode renders all metrics in the Prometheus text format.
+pub fn encode() -> String {
+    let mut out = String::new();
+    for family in families() {
+        family.encode(&mut out);
+    }
+    out
+}
+
+/// Family is a set of counters sharing a name, distinguished by their labels.
+pub struct Family {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<HashMap<Labels, u64>>,
+}
+
+impl Family {
+    pub fn new(name: &'static str, help: &'static str) -> Family {
+        Family {
+            name,
+            help,
+            values: Default::default(),
+        }
+    }
+
+    pub fn inc(&self, labels: Labels) {
+        self.inc_by(labels, 1)
+    }
+
+    pub fn inc_by(&self, labels: Labels, n: u64) {
+        *self.values.lock().unwrap().entry(labels).or_default() += n;
+    }
+
+    /// get returns the value of the counter with labels.
+    pub fn get(&self, labels: &Labels) -> u64 {
+        self.values
+            .lock()
+            .unwrap()
+            .get(labels)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn encode(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        let values = self.values.lock().unwrap();
+        // Sort for stable output.
+        let mut values: Vec<_> = values.iter().collect();
+        values.sort();
+        for (labels, value) in values {
+            out.push_str(self.name);
+            if !labels.is_empty() {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+                    .collect();
+                let _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(out, " {value}");
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_family() {
+        let family = Family::new("test_total", "A test counter");
+        family.inc(vec![("result", "success".to_string())]);
+        family.inc_by(vec![("result", "success".to_string())], 2);
+        family.inc(vec![("result", "quote\"d".to_string())]);
+
+        let mut out = String::new();
+        family.encode(&mut out);
+        assert_eq!(
+            out,
+            "# HELP test_total A test counter\n\
+             # TYPE test_total counter\n\
+             test_total{result=\"quote\\\"d\"} 1\n\
+             test_total{result=\"success\"} 3\n"
+        );
+        assert_eq!(family.get(&vec![("result", "success".to_string())]), 3);
+        assert_eq!(family.get(&vec![("result", "missing".to_string())]), 0);
+    }
+}
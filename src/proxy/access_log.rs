@@ -0,0 +1,453 @@
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, warn};
+
+use crate::proxy::{ConnectionRecord, Direction, PeerMetadata, RecordSink};
+use crate::workload::Protocol;
+
+/// The format of text access logs, unless configured otherwise. Each %FIELD% is replaced by the
+/// field of the same name in the JSON format.
+pub const DEFAULT_TEXT_FORMAT: &str = "[%START_TIME%] %DIRECTION% %PROTOCOL% %RESPONSE_FLAGS% \
+    %BYTES_SENT% %BYTES_RECEIVED% %DURATION% %DOWNSTREAM_REMOTE_ADDRESS% %UPSTREAM_HOST% \
+    %SOURCE_PRINCIPAL% %DESTINATION_PRINCIPAL% %CONNECTION_TERMINATION_DETAILS%";
+
+// Entries buffered for the writer before new ones are dropped.
+const BUFFERED_ENTRIES: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    /// The file access logs are appended to. If unset, they are written to stdout.
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Json,
+    /// A template in which each %FIELD% is replaced with the value of the field.
+    Text(Template),
+}
+
+/// Template is a text access log format, parsed once into the literal text and fields it is made
+/// of, so lines are rendered in a single pass. Field values are never expanded themselves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// Field is a field of an access log entry, referred to as %FIELD% in text templates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    StartTime,
+    Direction,
+    Protocol,
+    ResponseFlags,
+    BytesSent,
+    BytesReceived,
+    Duration,
+    DownstreamRemoteAddress,
+    UpstreamHost,
+    SourceWorkload,
+    SourceNamespace,
+    SourceCluster,
+    SourcePrincipal,
+    DestinationWorkload,
+    DestinationNamespace,
+    DestinationCluster,
+    DestinationPrincipal,
+    ConnectionTerminationDetails,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        Some(match name {
+            "START_TIME" => Field::StartTime,
+            "DIRECTION" => Field::Direction,
+            "PROTOCOL" => Field::Protocol,
+            "RESPONSE_FLAGS" => Field::ResponseFlags,
+            "BYTES_SENT" => Field::BytesSent,
+            "BYTES_RECEIVED" => Field::BytesReceived,
+            "DURATION" => Field::Duration,
+            "DOWNSTREAM_REMOTE_ADDRESS" => Field::DownstreamRemoteAddress,
+            "UPSTREAM_HOST" => Field::UpstreamHost,
+            "SOURCE_WORKLOAD" => Field::SourceWorkload,
+            "SOURCE_NAMESPACE" => Field::SourceNamespace,
+            "SOURCE_CLUSTER" => Field::SourceCluster,
+            "SOURCE_PRINCIPAL" => Field::SourcePrincipal,
+            "DESTINATION_WORKLOAD" => Field::DestinationWorkload,
+            "DESTINATION_NAMESPACE" => Field::DestinationNamespace,
+            "DESTINATION_CLUSTER" => Field::DestinationCluster,
+            "DESTINATION_PRINCIPAL" => Field::DestinationPrincipal,
+            "CONNECTION_TERMINATION_DETAILS" => Field::ConnectionTerminationDetails,
+            _ => return None,
+        })
+    }
+}
+
+impl Template {
+    /// new parses a template. Text between percent signs that is not a known field is kept as is.
+    pub fn new(template: &str) -> Template {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('%') {
+            let after = &rest[start + 1..];
+            let field = after
+                .find('%')
+                .and_then(|end| Some((Field::from_name(&after[..end])?, end)));
+            match field {
+                Some((field, end)) => {
+                    literal.push_str(&rest[..start]);
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field(field));
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    // The closing % may open a field itself, so only this one is consumed.
+                    literal.push_str(&rest[..=start]);
+                    rest = after;
+                }
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Template {
+            source: template.to_string(),
+            segments,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    fn render(&self, record: &ConnectionRecord) -> String {
+        fn peer_field<'a>(
+            out: &mut String,
+            peer: &'a Option<PeerMetadata>,
+            f: impl Fn(&'a PeerMetadata) -> &'a str,
+        ) -> fmt::Result {
+            match peer.as_ref().map(f).filter(|v| !v.is_empty()) {
+                Some(v) => out.write_str(v),
+                None => out.write_str("-"),
+            }
+        }
+        fn optional(out: &mut String, v: Option<impl fmt::Display>) -> fmt::Result {
+            match v {
+                Some(v) => write!(out, "{v}"),
+                None => out.write_str("-"),
+            }
+        }
+        let (src, dst) = (record.source_peer(), record.destination_peer());
+        let mut line = String::with_capacity(self.source.len() * 2);
+        for segment in &self.segments {
+            let field = match segment {
+                Segment::Literal(text) => {
+                    line.push_str(text);
+                    continue;
+                }
+                Segment::Field(field) => field,
+            };
+            // Writing to a String cannot fail.
+            let _ = match field {
+                Field::StartTime => write!(line, "{}", Rfc3339(record.start)),
+                Field::Direction => line.write_str(direction_name(&record.direction)),
+                Field::Protocol => line.write_str(protocol_name(&record.protocol)),
+                Field::ResponseFlags => line.write_str(record.close_reason.response_flags()),
+                Field::BytesSent => write!(line, "{}", record.bytes_sent),
+                Field::BytesReceived => write!(line, "{}", record.bytes_received),
+                Field::Duration => write!(line, "{}", record.duration.as_millis()),
+                Field::DownstreamRemoteAddress => write!(line, "{}", record.source),
+                Field::UpstreamHost => write!(line, "{}", record.destination),
+                Field::SourceWorkload => peer_field(&mut line, &src, |p| p.workload_name.as_str()),
+                Field::SourceNamespace => peer_field(&mut line, &src, |p| p.namespace.as_str()),
+                Field::SourceCluster => peer_field(&mut line, &src, |p| p.cluster_id.as_str()),
+                Field::SourcePrincipal => optional(&mut line, record.source_identity.as_ref()),
+                Field::DestinationWorkload => {
+                    peer_field(&mut line, &dst, |p| p.workload_name.as_str())
+                }
+                Field::DestinationNamespace => {
+                    peer_field(&mut line, &dst, |p| p.namespace.as_str())
+                }
+                Field::DestinationCluster => peer_field(&mut line, &dst, |p| p.cluster_id.as_str()),
+                Field::DestinationPrincipal => {
+                    optional(&mut line, record.destination_identity.as_ref())
+                }
+                Field::ConnectionTerminationDetails => optional(&mut line, record.error.as_ref()),
+            };
+        }
+        line
+    }
+}
+
+/// AccessLog writes a line for every proxied connection once it closes. Lines are written by a
+/// dedicated thread, so slow output never blocks proxying; if it falls too far behind, entries are
+/// dropped.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    entries: mpsc::SyncSender<String>,
+}
+
+impl AccessLog {
+    pub fn new(cfg: &AccessLogConfig) -> io::Result<AccessLog> {
+        let out: Box<dyn Write + Send> = match &cfg.path {
+            Some(path) => Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+        let (entries, rx) = mpsc::sync_channel(BUFFERED_ENTRIES);
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_entries(rx, out))?;
+        Ok(AccessLog {
+            format: cfg.format.clone(),
+            entries,
+        })
+    }
+}
+
+impl RecordSink for AccessLog {
+    fn record(&self, record: &ConnectionRecord) {
+        let line = format_entry(&self.format, record);
+        if let Err(mpsc::TrySendError::Full(_)) = self.entries.try_send(line) {
+            debug!("access log is behind, dropping entry");
+        }
+    }
+}
+
+fn write_entries(entries: mpsc::Receiver<String>, out: Box<dyn Write + Send>) {
+    let mut out = io::BufWriter::new(out);
+    while let Ok(line) = entries.recv() {
+        let mut res = writeln!(out, "{line}");
+        // Batch whatever else is pending into a single flush.
+        while res.is_ok() {
+            match entries.try_recv() {
+                Ok(line) => res = writeln!(out, "{line}"),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = res.and_then(|_| out.flush()) {
+            warn!("failed to write access log: {}", e);
+        }
+    }
+}
+
+/// Entry is the access log entry of a connection. Field names follow Istio's standard access log
+/// and metric labels.
+#[derive(serde::Serialize)]
+struct Entry<'a> {
+    start_time: String,
+    direction: &'static str,
+    protocol: &'static str,
+    response_flags: &'static str,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Milliseconds.
+    duration: u64,
+    downstream_remote_address: String,
+    upstream_host: String,
+    source_workload: Option<String>,
+    source_namespace: Option<String>,
+    source_cluster: Option<String>,
+    source_principal: Option<String>,
+    destination_workload: Option<String>,
+    destination_namespace: Option<String>,
+    destination_cluster: Option<String>,
+    destination_principal: Option<String>,
+    connection_termination_details: Option<&'a str>,
+}
+
+impl<'a> Entry<'a> {
+    fn new(record: &'a ConnectionRecord) -> Entry<'a> {
+        fn field(
+            peer: &Option<PeerMetadata>,
+            f: impl Fn(&PeerMetadata) -> &String,
+        ) -> Option<String> {
+            peer.as_ref().map(f).filter(|v| !v.is_empty()).cloned()
+        }
+        let (src, dst) = (record.source_peer(), record.destination_peer());
+        Entry {
+            start_time: Rfc3339(record.start).to_string(),
+            direction: direction_name(&record.direction),
+            protocol: protocol_name(&record.protocol),
+            response_flags: record.close_reason.response_flags(),
+            bytes_sent: record.bytes_sent,
+            bytes_received: record.bytes_received,
+            duration: record.duration.as_millis() as u64,
+            downstream_remote_address: record.source.to_string(),
+            upstream_host: record.destination.to_string(),
+            source_workload: field(&src, |p| &p.workload_name),
+            source_namespace: field(&src, |p| &p.namespace),
+            source_cluster: field(&src, |p| &p.cluster_id),
+            source_principal: record.source_identity.as_ref().map(ToString::to_string),
+            destination_workload: field(&dst, |p| &p.workload_name),
+            destination_namespace: field(&dst, |p| &p.namespace),
+            destination_cluster: field(&dst, |p| &p.cluster_id),
+            destination_principal: record
+                .destination_identity
+                .as_ref()
+                .map(ToString::to_string),
+            connection_termination_details: record.error.as_deref(),
+        }
+    }
+}
+
+fn direction_name(direction: &Direction) -> &'static str {
+    match direction {
+        Direction::Inbound => "inbound",
+        Direction::Outbound => "outbound",
+    }
+}
+
+fn protocol_name(protocol: &Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "TCP",
+        Protocol::Hbone => "HBONE",
+    }
+}
+
+fn format_entry(format: &AccessLogFormat, record: &ConnectionRecord) -> String {
+    match format {
+        AccessLogFormat::Json => {
+            serde_json::to_string(&Entry::new(record)).expect("entry must serialize")
+        }
+        AccessLogFormat::Text(template) => template.render(record),
+    }
+}
+
+/// Rfc3339 displays a time as an RFC 3339 timestamp in UTC, with millisecond precision.
+struct Rfc3339(SystemTime);
+
+impl fmt::Display for Rfc3339 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+        // Convert days since the epoch to a civil date; see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::identity::Identity;
+    use crate::workload::Workload;
+    use crate::xds::istio::workload::Workload as XdsWorkload;
+
+    fn test_record() -> ConnectionRecord {
+        let mut record = ConnectionRecord::new(
+            Direction::Outbound,
+            Protocol::Hbone,
+            "10.0.0.1:41000".parse().unwrap(),
+            "10.0.0.2:8080".parse().unwrap(),
+        );
+        record.start = UNIX_EPOCH + Duration::from_millis(1_668_000_000_123);
+        record.duration = Duration::from_millis(1500);
+        record.bytes_sent = 100;
+        record.bytes_received = 2000;
+        let workload = XdsWorkload {
+            name: "sleep-abc".to_string(),
+            namespace: "default".to_string(),
+            workload_name: "sleep".to_string(),
+            address: Bytes::copy_from_slice(&[10, 0, 0, 1]),
+            ..Default::default()
+        };
+        record.source_workload = Some(Workload::try_from(&workload).unwrap());
+        record.source_identity = Some(Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "default".to_string(),
+            service_account: "sleep".to_string(),
+        });
+        record
+    }
+
+    #[test]
+    fn json() {
+        let line = format_entry(&AccessLogFormat::Json, &test_record());
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["start_time"], "2022-11-09T13:20:00.123Z");
+        assert_eq!(entry["direction"], "outbound");
+        assert_eq!(entry["protocol"], "HBONE");
+        assert_eq!(entry["response_flags"], "-");
+        assert_eq!(entry["bytes_sent"], 100);
+        assert_eq!(entry["bytes_received"], 2000);
+        assert_eq!(entry["duration"], 1500);
+        assert_eq!(entry["source_workload"], "sleep");
+        assert_eq!(entry["source_namespace"], "default");
+        assert_eq!(
+            entry["source_principal"],
+            "spiffe://cluster.local/ns/default/sa/sleep"
+        );
+        assert_eq!(entry["destination_workload"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn text() {
+        let mut record = test_record();
+        record.connect_failed("connection refused");
+        record.duration = Duration::from_millis(1500);
+        let format = AccessLogFormat::Text(Template::new(
+            "%DIRECTION% %RESPONSE_FLAGS% %UPSTREAM_HOST% %DESTINATION_WORKLOAD% \
+             %CONNECTION_TERMINATION_DETAILS% %UNKNOWN% 100%%DURATION%",
+        ));
+        assert_eq!(
+            format_entry(&format, &record),
+            "outbound UF 10.0.0.2:8080 - connection refused %UNKNOWN% 100%1500"
+        );
+
+        // Values are never expanded as fields themselves.
+        record.error = Some("%DIRECTION%".to_string());
+        let format = AccessLogFormat::Text(Template::new("%CONNECTION_TERMINATION_DETAILS%"));
+        assert_eq!(format_entry(&format, &record), "%DIRECTION%");
+    }
+
+    #[test]
+    fn time() {
+        assert_eq!(Rfc3339(UNIX_EPOCH).to_string(), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            Rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400)).to_string(),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+}
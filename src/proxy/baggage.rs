@@ -0,0 +1,141 @@
+//! Peer metadata exchanged over HBONE in the W3C baggage header
+//! (https://www.w3.org/TR/baggage/). The client sends its own metadata on the CONNECT request and
+//! the server answers with the destination's, so both sides can describe their peer in telemetry.
+
+use std::fmt;
+
+use crate::workload::Workload;
+
+pub const BAGGAGE_HEADER: &str = "baggage";
+
+/// PeerMetadata describes a workload at one end of a connection. Empty fields are unknown.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerMetadata {
+    pub cluster_id: String,
+    pub namespace: String,
+    /// The kind of the workload's owner, such as deployment.
+    pub workload_type: String,
+    pub workload_name: String,
+    pub canonical_name: String,
+    pub canonical_revision: String,
+}
+
+impl PeerMetadata {
+    pub fn from_workload(w: &Workload, cluster_id: &str) -> PeerMetadata {
+        PeerMetadata {
+            cluster_id: cluster_id.to_string(),
+            namespace: w.namespace.clone(),
+            workload_type: w.workload_type.clone(),
+            workload_name: w.workload_name().to_string(),
+            canonical_name: w.canonical_name.clone(),
+            canonical_revision: w.canonical_revision.clone(),
+        }
+    }
+
+    /// from_headers parses the peer metadata in the baggage of a request or response, if any.
+    pub fn from_headers(headers: &hyper::HeaderMap) -> Option<PeerMetadata> {
+        let mut meta = PeerMetadata::default();
+        for value in headers.get_all(BAGGAGE_HEADER) {
+            meta.merge_baggage(value.to_str().ok()?);
+        }
+        (meta != PeerMetadata::default()).then_some(meta)
+    }
+
+    /// merge_baggage sets the fields found in a baggage header value, ignoring unknown members.
+    fn merge_baggage(&mut self, baggage: &str) {
+        for member in baggage.split(',') {
+            // Members may carry properties after a semicolon, which we have no use for.
+            let member = member.split(';').next().unwrap_or_default();
+            let Some((key, value)) = member.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            match key.trim() {
+                "k8s.cluster.name" => self.cluster_id = value,
+                "k8s.namespace.name" => self.namespace = value,
+                "service.name" => self.canonical_name = value,
+                "service.version" => self.canonical_revision = value,
+                key => {
+                    // The workload is keyed by its type, as in k8s.deployment.name.
+                    if let Some(workload_type) = key
+                        .strip_prefix("k8s.")
+                        .and_then(|k| k.strip_suffix(".name"))
+                    {
+                        self.workload_type = workload_type.to_string();
+                        self.workload_name = value;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Display formats the metadata as a baggage header value. Values are Kubernetes names and labels,
+/// which never need escaping.
+impl fmt::Display for PeerMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let workload_key = format!("k8s.{}.name", self.workload_type);
+        let members = [
+            ("k8s.cluster.name", &self.cluster_id),
+            ("k8s.namespace.name", &self.namespace),
+            (workload_key.as_str(), &self.workload_name),
+            ("service.name", &self.canonical_name),
+            ("service.version", &self.canonical_revision),
+        ];
+        let mut first = true;
+        for (key, value) in members {
+            if value.is_empty() || (key == workload_key && self.workload_type.is_empty()) {
+                continue;
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            write!(f, "{key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baggage() {
+        let meta = PeerMetadata {
+            cluster_id: "Kubernetes".to_string(),
+            namespace: "default".to_string(),
+            workload_type: "deployment".to_string(),
+            workload_name: "sleep".to_string(),
+            canonical_name: "sleep".to_string(),
+            canonical_revision: String::new(),
+        };
+        let value = meta.to_string();
+        assert_eq!(
+            value,
+            "k8s.cluster.name=Kubernetes,k8s.namespace.name=default,\
+             k8s.deployment.name=sleep,service.name=sleep"
+        );
+
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(BAGGAGE_HEADER, value.parse().unwrap());
+        assert_eq!(PeerMetadata::from_headers(&headers), Some(meta));
+
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(
+            BAGGAGE_HEADER,
+            " k8s.namespace.name = ns ;prop=1, other=x,malformed"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            PeerMetadata::from_headers(&headers),
+            Some(PeerMetadata {
+                namespace: "ns".to_string(),
+                ..Default::default()
+            })
+        );
+        assert_eq!(PeerMetadata::from_headers(&hyper::HeaderMap::new()), None);
+    }
+}
@@ -1,24 +1,39 @@
-use std::net::SocketAddr;
-use std::os::unix::io::RawFd;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
+use drain::Watch;
+use hyper::header::HeaderValue;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream, Http};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
-use tokio::net::TcpListener;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::StreamExt;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 use crate::config::Config;
-use crate::identity;
+use crate::identity::{self, Identity};
+use crate::socket::CaptureMode;
+use crate::telemetry::trace::{self, TraceContext};
 use crate::tls::TlsError;
-use crate::workload::WorkloadInformation;
+use crate::workload::{Protocol, Workload, WorkloadInformation};
 
-use super::Error;
+use super::record::{self, ConnectionRecord};
+use super::{
+    baggage, ConnectionLimiter, ConnectionTracker, Error, PeerMetadata, Recorder, Transfer,
+};
 
 pub struct Inbound {
     cfg: Config,
     listener: TcpListener,
     cert_manager: identity::SecretManager,
     workloads: WorkloadInformation,
+    drain: Watch,
+    connections: ConnectionTracker,
+    limiter: ConnectionLimiter,
 }
 
 impl Inbound {
@@ -26,6 +41,9 @@ impl Inbound {
         cfg: Config,
         workloads: WorkloadInformation,
         cert_manager: identity::SecretManager,
+        drain: Watch,
+        connections: ConnectionTracker,
+        limiter: ConnectionLimiter,
     ) -> Result<Inbound, Error> {
         let listener: TcpListener = TcpListener::bind(cfg.inbound_addr)
             .await
@@ -39,6 +57,9 @@ impl Inbound {
             workloads,
             listener,
             cert_manager,
+            drain,
+            connections,
+            limiter,
         })
     }
 
@@ -46,39 +67,74 @@ impl Inbound {
         let addr = self.listener.local_addr().unwrap();
         if self.cfg.tls {
             // TODO avoid duplication here
-            let service = make_service_fn(|_| async {
-                Ok::<_, hyper::Error>(service_fn(Self::serve_connect))
+            let cfg = self.cfg.clone();
+            let workloads = self.workloads.clone();
+            let drain = self.drain.clone();
+            let connections = self.connections.clone();
+            let service = make_service_fn(move |conn: &tokio_boring::SslStream<AddrStream>| {
+                let downstream = Downstream {
+                    addr: conn.get_ref().remote_addr(),
+                    identity: crate::tls::peer_identity(conn.ssl()),
+                    orig: Self::original_destination(&cfg, conn.get_ref()),
+                };
+                super::set_keepalive(&cfg, conn.get_ref());
+                let cfg = cfg.clone();
+                let workloads = workloads.clone();
+                let recorder = connections.recorder();
+                let guard = connections.track(drain.clone());
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        // The service lives as long as the connection, so it holds the guard.
+                        let _ = &guard;
+                        Self::serve_connect(
+                            cfg.clone(),
+                            workloads.clone(),
+                            recorder.clone(),
+                            downstream.clone(),
+                            req,
+                        )
+                    }))
+                }
             });
             let boring_acceptor = crate::tls::BoringTlsAcceptor {
                 acceptor: InboundCertProvider {
                     workloads: self.workloads.clone(),
                     cert_manager: self.cert_manager.clone(),
+                    capture_mode: self.cfg.capture_mode,
                 },
             };
-            let mut listener = hyper::server::conn::AddrIncoming::from_listener(self.listener)
-                .expect("hbone bind");
+            let mut listener = AddrIncoming::from_listener(self.listener).expect("hbone bind");
             listener.set_nodelay(true);
+            let listener = BypassNativeHbone::new(
+                listener,
+                NativeForwarder {
+                    cfg: self.cfg.clone(),
+                    workloads: self.workloads.clone(),
+                    connections: self.connections.clone(),
+                    drain: self.drain.clone(),
+                    limiter: self.limiter.clone(),
+                },
+            );
             let incoming = hyper::server::accept::from_stream(
                 tls_listener::builder(boring_acceptor)
                     .listen(listener)
                     .filter(|conn| {
                         // Avoid 'By default, if a client fails the TLS handshake, that is treated as an error, and the TlsListener will return an Err'
                         if let Err(err) = conn {
+                            crate::metrics::LISTENER_ERRORS
+                                .inc(vec![("listener", "inbound".to_string())]);
                             warn!("TLS handshake error: {}", err);
                             false
                         } else {
-                            info!("TLS handshake succeeded");
+                            debug!("TLS handshake succeeded");
                             true
                         }
                     }),
             );
 
-            let server = Server::builder(incoming)
-                .http2_only(true)
-                .http2_initial_stream_window_size(self.cfg.window_size)
-                .http2_initial_connection_window_size(self.cfg.connection_window_size)
-                .http2_max_frame_size(self.cfg.frame_size)
-                .serve(service);
+            let server = Self::h2_server(&self.cfg, incoming)
+                .serve(service)
+                .with_graceful_shutdown(Self::drained(self.drain.clone()));
 
             info!("HBONE listener established {}", addr);
 
@@ -87,18 +143,40 @@ impl Inbound {
             }
         } else {
             warn!("TLS disabled");
-            let service = make_service_fn(|_| async {
-                Ok::<_, hyper::Error>(service_fn(Self::serve_connect))
+            let cfg = self.cfg.clone();
+            let workloads = self.workloads.clone();
+            let drain = self.drain.clone();
+            let connections = self.connections.clone();
+            let service = make_service_fn(move |conn: &AddrStream| {
+                let downstream = Downstream {
+                    addr: conn.remote_addr(),
+                    identity: None,
+                    orig: Self::original_destination(&cfg, conn),
+                };
+                super::set_keepalive(&cfg, conn);
+                let cfg = cfg.clone();
+                let workloads = workloads.clone();
+                let recorder = connections.recorder();
+                let guard = connections.track(drain.clone());
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        // The service lives as long as the connection, so it holds the guard.
+                        let _ = &guard;
+                        Self::serve_connect(
+                            cfg.clone(),
+                            workloads.clone(),
+                            recorder.clone(),
+                            downstream.clone(),
+                            req,
+                        )
+                    }))
+                }
             });
 
-            let server = hyper::server::conn::AddrIncoming::from_listener(self.listener)
-                .map(Server::builder)
-                .expect("hbone bind")
-                .http2_only(true)
-                .http2_initial_stream_window_size(self.cfg.window_size)
-                .http2_initial_connection_window_size(self.cfg.connection_window_size)
-                .http2_max_frame_size(self.cfg.frame_size)
-                .serve(service);
+            let incoming = AddrIncoming::from_listener(self.listener).expect("hbone bind");
+            let server = Self::h2_server(&self.cfg, incoming)
+                .serve(service)
+                .with_graceful_shutdown(Self::drained(self.drain.clone()));
 
             info!("HBONE listener established {}", addr);
 
@@ -107,31 +185,137 @@ impl Inbound {
             }
         }
     }
-    async fn serve_connect(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-        let mut res = Response::new(Body::empty());
+
+    /// h2_server builds an HBONE server accepting from incoming, with the HTTP/2 settings of HBONE
+    /// servers. The header size limit is advertised to clients in SETTINGS_MAX_HEADER_LIST_SIZE, and
+    /// requests exceeding it are refused with a 431 by h2 itself.
+    fn h2_server<I>(cfg: &Config, incoming: I) -> hyper::server::Builder<I> {
+        let mut protocol = Http::new();
+        protocol
+            .http2_only(true)
+            .http2_initial_stream_window_size(cfg.window_size)
+            .http2_initial_connection_window_size(cfg.connection_window_size)
+            .http2_max_frame_size(cfg.frame_size)
+            .http2_max_header_list_size(
+                u32::try_from(cfg.http2_max_header_size).unwrap_or(u32::MAX),
+            )
+            .http2_adaptive_window(cfg.http2_adaptive_window)
+            .http2_keep_alive_interval(cfg.http2_keepalive_interval)
+            .http2_keep_alive_timeout(cfg.http2_keepalive_timeout)
+            .http2_max_concurrent_streams(cfg.http2_max_concurrent_streams);
+        hyper::server::Builder::new(incoming, protocol)
+    }
+
+    /// drained completes once a drain is signaled. Passed to hyper's graceful shutdown, this stops
+    /// accepting and sends HTTP/2 GOAWAY to open connections, then waits for them to complete.
+    async fn drained(drain: Watch) {
+        let _ = drain.signaled().await;
+        info!("inbound drained");
+    }
+
+    /// original_destination returns the address the client originally dialed, which determines the
+    /// certificate served to it. This is unavailable when the connection was not captured.
+    fn original_destination(cfg: &Config, conn: &AddrStream) -> Option<IpAddr> {
+        crate::socket::orig_dst_addr_fd(conn.as_raw_fd(), cfg.capture_mode)
+            .ok()
+            .map(super::to_canonical_ip)
+    }
+
+    async fn serve_connect(
+        cfg: Config,
+        workloads: WorkloadInformation,
+        recorder: Recorder,
+        downstream: Downstream,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
         match req.method() {
             &Method::CONNECT => {
                 // TODO: uri or host?
                 let uri = req.uri();
-
-                let addr: SocketAddr = uri.to_string().as_str().parse().expect("must be an addr");
-                let mut stream = tokio::net::TcpStream::connect(addr).await.expect("connect");
-                info!("Got {} request to {}", req.method(), uri);
-                *res.status_mut() = StatusCode::OK;
+                debug!("Got {} request to {}", req.method(), uri);
+                let addr = match Self::parse_destination(uri) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        warn!("rejecting CONNECT to {}: {}", uri, e);
+                        return Ok(e.into_response());
+                    }
+                };
+                let mut record = ConnectionRecord::new(
+                    record::Direction::Inbound,
+                    Protocol::Hbone,
+                    downstream.addr,
+                    addr,
+                );
+                record.source_identity = downstream.identity;
+                record.source_metadata = PeerMetadata::from_headers(req.headers());
+                record.destination_workload = workloads
+                    .fetch_workload(&super::to_canonical_ip(addr))
+                    .await;
+                record.destination_identity =
+                    record.destination_workload.as_ref().map(|w| w.identity());
+                record.destination_metadata = record
+                    .destination_workload
+                    .as_ref()
+                    .map(|w| PeerMetadata::from_workload(w, &cfg.cluster_id));
+                let destination_baggage =
+                    record.destination_metadata.as_ref().map(|m| m.to_string());
+                // Continue the trace of the client, if it sent one.
+                let span = trace::start(
+                    "inbound",
+                    trace::SpanKind::Server,
+                    TraceContext::from_headers(req.headers()),
+                );
+                let source = Self::forwarded_source(&workloads, &downstream, req.headers());
+                let mut stream =
+                    match Self::connect_authorized(&cfg, &workloads, downstream.orig, source, addr)
+                        .await
+                    {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("rejecting CONNECT to {}: {}", uri, e);
+                            record.connect_failed(&e);
+                            span.end(&record);
+                            recorder.record(record);
+                            return Ok(e.into_response());
+                        }
+                    };
+                recorder.opened(&record);
                 tokio::task::spawn(async move {
-                    match hyper::upgrade::on(req).await {
-                        Ok(mut upgraded) => {
-                            super::copy_hbone("hbone server", &mut upgraded, &mut stream)
+                    let transfer = Transfer::new(cfg.idle_timeout);
+                    let proxy = async {
+                        match hyper::upgrade::on(req).await {
+                            Ok(mut upgraded) => {
+                                super::copy_hbone(
+                                    "hbone server",
+                                    &mut upgraded,
+                                    &mut stream,
+                                    &transfer,
+                                )
                                 .await
-                                .expect("hbone server copy");
-                        }
-                        Err(e) => {
-                            // Not sure if this can even happen
-                            error!("No upgrade {e}");
+                            }
+                            Err(e) => {
+                                // Not sure if this can even happen
+                                error!("No upgrade {e}");
+                                Err(Error::Http(e))
+                            }
                         }
+                    };
+                    let res = recorder.track(&mut record, &transfer, proxy).await;
+                    if let Err(e) = &res {
+                        warn!("hbone server copy failed: {}", e);
                     }
+                    record.finish(&transfer, &res);
+                    span.end(&record);
+                    recorder.record(record);
                 });
-                // Send back our 200. TODO: 503 on failure to connect
+                // Send back our 200, describing the destination to the client.
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = StatusCode::OK;
+                if let Some(baggage) =
+                    destination_baggage.and_then(|b| HeaderValue::from_str(&b).ok())
+                {
+                    res.headers_mut().insert(baggage::BAGGAGE_HEADER, baggage);
+                }
                 Ok(res)
             }
             // Return the 404 Not Found for other routes.
@@ -143,18 +327,343 @@ impl Inbound {
             }
         }
     }
+
+    fn parse_destination(uri: &hyper::Uri) -> Result<SocketAddr, InboundError> {
+        uri.to_string()
+            .as_str()
+            .parse()
+            .map_err(|_| InboundError::InvalidAddress(uri.to_string()))
+    }
+
+    /// connect_local serves a request from a workload on this node in-process, bypassing the
+    /// HBONE listener. The destination is subject to the same authorization as a CONNECT request,
+    /// and the connection is recorded on the inbound side like one; as no mTLS is involved, it is
+    /// recorded as plain TCP.
+    pub(super) async fn connect_local(
+        cfg: &Config,
+        workloads: &WorkloadInformation,
+        recorder: &Recorder,
+        source: &Workload,
+        downstream: SocketAddr,
+        addr: SocketAddr,
+        trace_context: Option<&TraceContext>,
+    ) -> Result<(TcpStream, LocalConnection), Error> {
+        debug!("Got in-process request to {}", addr);
+        let mut record =
+            ConnectionRecord::new(record::Direction::Inbound, Protocol::Tcp, downstream, addr);
+        record.source_workload = Some(source.clone());
+        if !source.service_account.is_empty() {
+            record.source_identity = Some(source.identity());
+        }
+        record.source_metadata = Some(PeerMetadata::from_workload(source, &cfg.cluster_id));
+        record.destination_workload = workloads
+            .fetch_workload(&super::to_canonical_ip(addr))
+            .await;
+        record.destination_identity = record.destination_workload.as_ref().map(|w| w.identity());
+        record.destination_metadata = record
+            .destination_workload
+            .as_ref()
+            .map(|w| PeerMetadata::from_workload(w, &cfg.cluster_id));
+        let span = trace::start("inbound", trace::SpanKind::Server, trace_context.cloned());
+        match Self::connect_authorized(cfg, workloads, None, Some(source.workload_ip), addr).await {
+            Ok(stream) => {
+                recorder.opened(&record);
+                let local = LocalConnection {
+                    record,
+                    span,
+                    recorder: recorder.clone(),
+                };
+                Ok((stream, local))
+            }
+            Err(e) => {
+                record.connect_failed(&e);
+                span.end(&record);
+                recorder.record(record);
+                Err(match e {
+                    InboundError::ConnectFailed(_, e) => Error::Io(e),
+                    InboundError::ConnectTimeout(addr) => Error::ConnectTimeout(addr),
+                    e => Error::LocalRejected(e.to_string()),
+                })
+            }
+        }
+    }
+
+    /// forwarded_source returns the client address a CONNECT request was forwarded for, to connect
+    /// to the destination from in TPROXY mode. The peer of an HBONE connection is the client's
+    /// ztunnel, not the client, so the address is only trusted if the peer authenticated as the
+    /// workload at that address. Otherwise, we connect from our own address.
+    fn forwarded_source(
+        workloads: &WorkloadInformation,
+        downstream: &Downstream,
+        headers: &hyper::HeaderMap,
+    ) -> Option<IpAddr> {
+        let identity = downstream.identity.as_ref()?;
+        let ip = super::parse_forwarded_for(headers)?;
+        workloads
+            .find_workload(&ip)
+            .filter(|wl| &wl.identity() == identity)
+            .map(|_| ip)
+    }
+
+    async fn connect_authorized(
+        cfg: &Config,
+        workloads: &WorkloadInformation,
+        orig: Option<IpAddr>,
+        source: Option<IpAddr>,
+        addr: SocketAddr,
+    ) -> Result<TcpStream, InboundError> {
+        Self::authorize_destination(cfg, workloads, orig, addr).await?;
+        match tokio::time::timeout(cfg.connect_timeout, super::dial(cfg, addr, source)).await {
+            Ok(Ok(stream)) => {
+                super::set_keepalive(cfg, &stream);
+                Ok(stream)
+            }
+            Ok(Err(e)) => Err(InboundError::ConnectFailed(addr, e)),
+            Err(_) => Err(InboundError::ConnectTimeout(addr)),
+        }
+    }
+
+    /// authorize_destination ensures we only relay to workloads running on this node (or explicitly
+    /// allowed addresses), and only to the destination the client's connection was intended for.
+    /// Without this, the inbound listener would be an open relay into the node's network.
+    async fn authorize_destination(
+        cfg: &Config,
+        workloads: &WorkloadInformation,
+        orig: Option<IpAddr>,
+        addr: SocketAddr,
+    ) -> Result<(), InboundError> {
+        let ip = super::to_canonical_ip(addr);
+        if let Some(orig) = orig {
+            if orig != ip {
+                return Err(InboundError::DestinationMismatch(addr, orig));
+            }
+        }
+        if cfg.inbound_allowed_addresses.contains(&ip) {
+            return Ok(());
+        }
+        match workloads.fetch_workload(&ip).await {
+            Some(wl) if !wl.node.is_empty() && cfg.local_node.as_ref() == Some(&wl.node) => Ok(()),
+            _ => Err(InboundError::Forbidden(addr)),
+        }
+    }
+}
+
+/// LocalConnection is the inbound side of a connection served in-process. It is recorded once the
+/// outbound side is done proxying.
+pub(super) struct LocalConnection {
+    record: ConnectionRecord,
+    span: trace::Span,
+    recorder: Recorder,
+}
+
+impl LocalConnection {
+    /// run drives proxy to completion, recording the connection once it is done.
+    pub(super) async fn run<F>(mut self, transfer: &Transfer, proxy: F) -> Result<(), Error>
+    where
+        F: std::future::Future<Output = Result<(), Error>>,
+    {
+        let res = self.recorder.track(&mut self.record, transfer, proxy).await;
+        self.finish(transfer, &res);
+        res
+    }
+
+    pub(super) fn finish(mut self, transfer: &Transfer, res: &Result<(), Error>) {
+        self.record.finish(transfer, res);
+        self.span.end(&self.record);
+        self.recorder.record(self.record);
+    }
+}
+
+/// BypassNativeHbone accepts connections for the HBONE listener, except those to workloads that
+/// terminate HBONE themselves, such as sidecars. Those are not intercepted: their bytes are forwarded
+/// untouched to the original destination.
+struct BypassNativeHbone {
+    incoming: AddrIncoming,
+    forwarder: NativeForwarder,
+}
+
+impl BypassNativeHbone {
+    fn new(incoming: AddrIncoming, forwarder: NativeForwarder) -> BypassNativeHbone {
+        BypassNativeHbone {
+            incoming,
+            forwarder,
+        }
+    }
+
+    /// classify forwards conn if its original destination is a native HBONE workload, and
+    /// otherwise returns it.
+    fn classify(&self, conn: AddrStream) -> Option<AddrStream> {
+        let capture_mode = self.forwarder.cfg.capture_mode;
+        let Ok(orig) = crate::socket::orig_dst_addr_fd(conn.as_raw_fd(), capture_mode) else {
+            return Some(conn);
+        };
+        let Some(destination) = self.forwarder.native_destination(orig) else {
+            return Some(conn);
+        };
+        let source = conn.remote_addr();
+        let forwarder = self.forwarder.clone();
+        tokio::spawn(forwarder.forward(conn.into_inner(), source, orig, destination));
+        None
+    }
+}
+
+impl tls_listener::AsyncAccept for BypassNativeHbone {
+    type Connection = AddrStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<AddrStream, io::Error>>> {
+        loop {
+            match Accept::poll_accept(Pin::new(&mut self.incoming), cx) {
+                Poll::Ready(Some(Ok(conn))) => match self.classify(conn) {
+                    Some(conn) => return Poll::Ready(Some(Ok(conn))),
+                    // Forwarded; look for the next one.
+                    None => continue,
+                },
+                res => return res,
+            }
+        }
+    }
+}
+
+/// NativeForwarder forwards connections to native HBONE workloads. Though their bytes are opaque to
+/// us, they are tracked, limited and recorded like any connection we proxy.
+#[derive(Clone)]
+struct NativeForwarder {
+    cfg: Config,
+    workloads: WorkloadInformation,
+    connections: ConnectionTracker,
+    drain: Watch,
+    limiter: ConnectionLimiter,
+}
+
+impl NativeForwarder {
+    /// native_destination returns the workload at orig if it is a native HBONE workload. Only
+    /// workloads already known are considered: this runs before the TLS handshake, so any
+    /// unauthenticated client could otherwise trigger on-demand workload requests.
+    fn native_destination(&self, orig: SocketAddr) -> Option<Workload> {
+        self.workloads
+            .find_workload(&super::to_canonical_ip(orig))
+            .filter(|wl| wl.native_hbone)
+    }
+
+    async fn forward(
+        self,
+        mut downstream: TcpStream,
+        source: SocketAddr,
+        orig: SocketAddr,
+        destination: Workload,
+    ) {
+        debug!("forwarding {} to native HBONE workload {}", source, orig);
+        let source_ip = super::to_canonical_ip(source);
+        let _limit_guard = match self.limiter.acquire(source_ip) {
+            Ok(guard) => guard,
+            Err(e) => {
+                warn!("rejecting connection from {} to {}: {}", source, orig, e);
+                return;
+            }
+        };
+        let _guard = self.connections.track(self.drain.clone());
+        let recorder = self.connections.recorder();
+        // The peer's mTLS is opaque to us, so we record the connection as plain TCP.
+        let mut record =
+            ConnectionRecord::new(record::Direction::Inbound, Protocol::Tcp, source, orig);
+        // The source is unauthenticated, so it must not trigger an on-demand workload request.
+        record.source_workload = self.workloads.find_workload(&source_ip);
+        record.destination_identity = Some(destination.identity());
+        record.destination_workload = Some(destination);
+        let span = trace::start("inbound_bypass", trace::SpanKind::Server, None);
+
+        let mut upstream = match super::connect_timeout(&self.cfg, orig, Some(source_ip)).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                warn!("forwarding to native HBONE workload {} failed: {}", orig, e);
+                record.connect_failed(&e);
+                span.end(&record);
+                recorder.record(record);
+                return;
+            }
+        };
+        recorder.opened(&record);
+        super::set_keepalive(&self.cfg, &downstream);
+        super::set_keepalive(&self.cfg, &upstream);
+        let transfer = Transfer::new(self.cfg.idle_timeout);
+        let copy = super::copy_tcp(&mut downstream, &mut upstream, self.cfg.splice, &transfer);
+        let res = recorder.track(&mut record, &transfer, copy).await;
+        if let Err(e) = &res {
+            warn!("forwarding to native HBONE workload {} failed: {}", orig, e);
+        }
+        record.finish(&transfer, &res);
+        span.end(&record);
+        recorder.record(record);
+    }
+}
+
+/// Downstream describes the connection a CONNECT request arrived on.
+#[derive(Clone)]
+struct Downstream {
+    addr: SocketAddr,
+    // The identity presented by the peer, when using TLS.
+    identity: Option<Identity>,
+    // The address the client originally dialed, if the connection was redirected.
+    orig: Option<IpAddr>,
+}
+
+/// The header carrying the reason a CONNECT request was rejected.
+const REASON_HEADER: &str = "x-ztunnel-reason";
+
+/// InboundError describes why a CONNECT request could not be served. Each error maps to the
+/// HTTP status returned to the client.
+#[derive(thiserror::Error, Debug)]
+enum InboundError {
+    #[error("invalid destination address {0}")]
+    InvalidAddress(String),
+    #[error("destination {0} is not a workload on this node")]
+    Forbidden(SocketAddr),
+    #[error("destination {0} does not match original destination {1}")]
+    DestinationMismatch(SocketAddr, IpAddr),
+    #[error("failed to connect to {0}: {1}")]
+    ConnectFailed(SocketAddr, #[source] io::Error),
+    #[error("timed out connecting to {0}")]
+    ConnectTimeout(SocketAddr),
+}
+
+impl InboundError {
+    fn status(&self) -> StatusCode {
+        match self {
+            InboundError::InvalidAddress(_) => StatusCode::BAD_REQUEST,
+            InboundError::Forbidden(_) | InboundError::DestinationMismatch(..) => {
+                StatusCode::FORBIDDEN
+            }
+            InboundError::ConnectFailed(..) => StatusCode::SERVICE_UNAVAILABLE,
+            InboundError::ConnectTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = self.status();
+        if let Ok(reason) = HeaderValue::from_str(&self.to_string()) {
+            res.headers_mut().insert(REASON_HEADER, reason);
+        }
+        res
+    }
 }
 
 #[derive(Clone)]
 struct InboundCertProvider {
     cert_manager: identity::SecretManager,
     workloads: WorkloadInformation,
+    capture_mode: CaptureMode,
 }
 
 #[async_trait::async_trait]
 impl crate::tls::CertProvider for InboundCertProvider {
     async fn fetch_cert(&self, fd: RawFd) -> Result<boring::ssl::SslAcceptor, TlsError> {
-        let orig = crate::socket::orig_dst_addr_fd(fd).map_err(TlsError::DestinationLookup)?;
+        let orig = crate::socket::orig_dst_addr_fd(fd, self.capture_mode)
+            .map_err(TlsError::DestinationLookup)?;
         let identity = {
             let remote_addr = super::to_canonical_ip(orig);
             self.workloads
@@ -162,9 +671,436 @@ impl crate::tls::CertProvider for InboundCertProvider {
                 .ok_or(TlsError::CertificateLookup(remote_addr))?
                 .identity()
         };
-        info!("tls: accepting connection to {:?} ({})", orig, identity);
+        debug!("tls: accepting connection to {:?} ({})", orig, identity);
         let cert = self.cert_manager.fetch_certificate(identity).await?;
         let acc = cert.acceptor()?;
         Ok(acc)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::proxy::record::tests::Records;
+    use crate::proxy::RecordSink;
+    use crate::workload::WorkloadStore;
+    use crate::xds::istio::workload::Workload as XdsWorkload;
+
+    use super::*;
+
+    fn test_workloads() -> WorkloadInformation {
+        let store = WorkloadStore::test_store(vec![
+            XdsWorkload {
+                name: "local".to_string(),
+                namespace: "ns".to_string(),
+                address: Bytes::copy_from_slice(&[127, 0, 0, 1]),
+                node: "local-node".to_string(),
+                ..Default::default()
+            },
+            XdsWorkload {
+                name: "remote".to_string(),
+                namespace: "ns".to_string(),
+                address: Bytes::copy_from_slice(&[127, 0, 0, 2]),
+                node: "remote-node".to_string(),
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+        WorkloadInformation {
+            info: Arc::new(Mutex::new(store)),
+            demand: None,
+        }
+    }
+
+    async fn connect_recorded(
+        uri: &str,
+        orig: Option<IpAddr>,
+        cfg: Config,
+        recorder: Recorder,
+    ) -> Response<Body> {
+        let req = Request::builder()
+            .method(Method::CONNECT)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let downstream = Downstream {
+            addr: "127.0.0.4:12345".parse().unwrap(),
+            identity: None,
+            orig,
+        };
+        Inbound::serve_connect(cfg, test_workloads(), recorder, downstream, req)
+            .await
+            .unwrap()
+    }
+
+    async fn connect(uri: &str, orig: Option<IpAddr>, cfg: Config) -> Response<Body> {
+        connect_recorded(uri, orig, cfg, Recorder::default()).await
+    }
+
+    fn test_config() -> Config {
+        Config {
+            local_node: Some("local-node".to_string()),
+            inbound_allowed_addresses: vec![],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn forwarded_source() {
+        let workloads = test_workloads();
+        let client: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(
+            hyper::header::FORWARDED,
+            crate::proxy::forwarded_for(client),
+        );
+        let downstream = |identity| Downstream {
+            addr: "10.0.0.1:15008".parse().unwrap(),
+            identity,
+            orig: None,
+        };
+
+        let peer = workloads.find_workload(&client).unwrap().identity();
+        assert_eq!(
+            Inbound::forwarded_source(&workloads, &downstream(Some(peer.clone())), &headers),
+            Some(client)
+        );
+        assert_eq!(
+            Inbound::forwarded_source(&workloads, &downstream(None), &headers),
+            None,
+            "unauthenticated peer"
+        );
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "other".to_string(),
+        };
+        assert_eq!(
+            Inbound::forwarded_source(&workloads, &downstream(Some(other)), &headers),
+            None,
+            "peer is not the client workload"
+        );
+        assert_eq!(
+            Inbound::forwarded_source(
+                &workloads,
+                &downstream(Some(peer)),
+                &hyper::HeaderMap::new()
+            ),
+            None,
+            "not forwarded"
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_connect_errors() {
+        let res = connect("example.com:80", None, test_config()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res.headers().get(REASON_HEADER).unwrap(),
+            "invalid destination address example.com:80"
+        );
+
+        // Bind and immediately drop a listener to find a port that is not listening.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let res = connect(&addr.to_string(), None, test_config()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().contains_key(REASON_HEADER));
+    }
+
+    #[tokio::test]
+    async fn header_list_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|_req: Request<Body>| async {
+                Ok::<_, hyper::Error>(Response::new(Body::empty()))
+            }))
+        });
+        let incoming = AddrIncoming::from_listener(listener).unwrap();
+        tokio::spawn(Inbound::h2_server(&test_config(), incoming).serve(service));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, conn) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(stream)
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let request = |size: usize| {
+            Request::builder()
+                .uri(format!("http://{addr}/"))
+                .header("x-large", "a".repeat(size))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = client.send_request(request(16)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .send_request(request(test_config().http2_max_header_size))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn keepalive_closes_stalled_connection() {
+        let cfg = Config {
+            http2_keepalive_interval: Some(Duration::from_millis(50)),
+            http2_keepalive_timeout: Duration::from_millis(50),
+            ..test_config()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|_req: Request<Body>| async {
+                Ok::<_, hyper::Error>(Response::new(Body::empty()))
+            }))
+        });
+        let incoming = AddrIncoming::from_listener(listener).unwrap();
+        tokio::spawn(Inbound::h2_server(&cfg, incoming).serve(service));
+
+        // Complete the HTTP/2 preface and settings, then stall without acknowledging anything.
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        stalled
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stalled.read_to_end(&mut buf))
+            .await
+            .expect("stalled connection was never closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn forward_native_hbone() {
+        // The native HBONE workload echoes back everything it receives.
+        let upstream = TcpListener::bind("127.0.0.3:0").await.unwrap();
+        let orig = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        let store = WorkloadStore::test_store(vec![XdsWorkload {
+            name: "sidecar".to_string(),
+            namespace: "ns".to_string(),
+            address: Bytes::copy_from_slice(&[127, 0, 0, 3]),
+            native_hbone: true,
+            ..Default::default()
+        }])
+        .unwrap();
+        let workloads = WorkloadInformation {
+            info: Arc::new(Mutex::new(store)),
+            demand: None,
+        };
+        let records = Arc::new(Records::default());
+        let recorder = Recorder::new(vec![records.clone() as Arc<dyn RecordSink>]);
+        let (_signal, drain) = drain::channel();
+        let forwarder = NativeForwarder {
+            cfg: test_config(),
+            workloads,
+            connections: ConnectionTracker::new(recorder),
+            drain,
+            limiter: ConnectionLimiter::new(&test_config()),
+        };
+        let destination = forwarder
+            .native_destination(orig)
+            .expect("native HBONE workload");
+        assert!(
+            forwarder
+                .native_destination("127.0.0.4:80".parse().unwrap())
+                .is_none(),
+            "unknown workload"
+        );
+
+        // Stand in for a connection accepted by the HBONE listener.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (downstream, source) = listener.accept().await.unwrap();
+        let forwarded = tokio::spawn(forwarder.forward(downstream, source, orig, destination));
+
+        // Not an HBONE handshake; the bytes must pass through untouched all the same.
+        let payload: Vec<u8> = (0..=255).cycle().take(16 * 1024).collect();
+        client.write_all(&payload).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, payload);
+        forwarded.await.unwrap();
+
+        let records = records.0.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].destination, orig);
+        assert_eq!(records[0].bytes_sent, payload.len() as u64);
+        assert_eq!(records[0].bytes_received, payload.len() as u64);
+    }
+
+    #[test]
+    fn parse_destination() {
+        for dst in ["127.0.0.1:80", "[fd00::1]:80", "[::ffff:127.0.0.1]:80"] {
+            let uri: hyper::Uri = dst.parse().unwrap();
+            assert_eq!(
+                Inbound::parse_destination(&uri).unwrap(),
+                dst.parse::<SocketAddr>().unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_connect_records() {
+        let records = Arc::new(Records::default());
+        let recorder = Recorder::new(vec![records.clone() as Arc<dyn RecordSink>]);
+        let res = connect_recorded("127.0.0.2:80", None, test_config(), recorder).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let records = records.0.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.direction, record::Direction::Inbound);
+        assert_eq!(record.source, "127.0.0.4:12345".parse().unwrap());
+        assert_eq!(record.destination, "127.0.0.2:80".parse().unwrap());
+        assert_eq!(
+            record
+                .destination_workload
+                .as_ref()
+                .map(|w| w.name.as_str()),
+            Some("remote")
+        );
+        assert_eq!(record.close_reason, record::CloseReason::ConnectFailed);
+    }
+
+    #[tokio::test]
+    async fn serve_connect_baggage() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let records = Arc::new(Records::default());
+        let recorder = Recorder::new(vec![records.clone() as Arc<dyn RecordSink>]);
+        let req = Request::builder()
+            .method(Method::CONNECT)
+            .uri(format!("127.0.0.1:{port}"))
+            .header(
+                baggage::BAGGAGE_HEADER,
+                "k8s.cluster.name=remote,k8s.namespace.name=client-ns",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let downstream = Downstream {
+            addr: "127.0.0.4:12345".parse().unwrap(),
+            identity: None,
+            orig: None,
+        };
+        let cfg = Config {
+            cluster_id: "local-cluster".to_string(),
+            ..test_config()
+        };
+        let res = Inbound::serve_connect(cfg, test_workloads(), recorder, downstream, req)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(baggage::BAGGAGE_HEADER).unwrap(),
+            "k8s.cluster.name=local-cluster,k8s.namespace.name=ns"
+        );
+
+        // The request was never upgraded, so the tunnel closes straight away.
+        let record = loop {
+            if let Some(record) = records.0.lock().unwrap().first() {
+                break record.clone();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let source = record.source_metadata.unwrap();
+        assert_eq!(source.cluster_id, "remote");
+        assert_eq!(source.namespace, "client-ns");
+    }
+
+    #[tokio::test]
+    async fn serve_connect_authorization() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let res = connect(&format!("127.0.0.1:{port}"), Some(local), test_config()).await;
+        assert_eq!(res.status(), StatusCode::OK, "workload on this node");
+
+        let res = connect(&format!("127.0.0.2:{port}"), None, test_config()).await;
+        assert_eq!(
+            res.status(),
+            StatusCode::FORBIDDEN,
+            "workload on other node"
+        );
+
+        let res = connect(&format!("127.0.0.3:{port}"), None, test_config()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "unknown address");
+
+        let res = connect(
+            &format!("127.0.0.1:{port}"),
+            Some("127.0.0.2".parse().unwrap()),
+            test_config(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "original dst mismatch");
+
+        let records = Arc::new(Records::default());
+        let recorder = Recorder::new(vec![records.clone() as Arc<dyn RecordSink>]);
+        let source = test_workloads().find_workload(&local).unwrap();
+        let downstream: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let res = Inbound::connect_local(
+            &test_config(),
+            &test_workloads(),
+            &recorder,
+            &source,
+            downstream,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+            None,
+        )
+        .await;
+        let (_stream, local_conn) = res.expect("in-process to workload on this node");
+        local_conn.finish(&Transfer::new(test_config().idle_timeout), &Ok(()));
+
+        let res = Inbound::connect_local(
+            &test_config(),
+            &test_workloads(),
+            &recorder,
+            &source,
+            downstream,
+            format!("127.0.0.2:{port}").parse().unwrap(),
+            None,
+        )
+        .await;
+        assert!(
+            matches!(res, Err(Error::LocalRejected(_))),
+            "in-process to workload on other node"
+        );
+
+        let records = records.0.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        for record in records.iter() {
+            assert_eq!(record.direction, record::Direction::Inbound);
+            assert_eq!(record.protocol, Protocol::Tcp);
+            assert_eq!(record.source, downstream);
+            assert_eq!(record.source_workload.as_ref(), Some(&source));
+        }
+        assert_eq!(records[1].close_reason, record::CloseReason::ConnectFailed);
+
+        let cfg = Config {
+            local_node: None,
+            inbound_allowed_addresses: vec![local],
+            ..Default::default()
+        };
+        let res = connect(&format!("127.0.0.1:{port}"), None, cfg).await;
+        assert_eq!(res.status(), StatusCode::OK, "allowlisted address");
+    }
+}
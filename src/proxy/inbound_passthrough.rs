@@ -1,20 +1,35 @@
-use super::Error;
+use super::record::{self, ConnectionRecord};
+use super::{ConnectionTracker, Error, Recorder, Transfer};
 
-use tokio::io;
-use tokio::io::AsyncWriteExt;
+use drain::Watch;
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 use crate::config::Config;
 use crate::socket;
+use crate::telemetry::trace;
+use crate::workload::{Protocol, WorkloadInformation};
 
 pub struct InboundPassthrough {
     cfg: Config,
+    workloads: WorkloadInformation,
+    drain: Watch,
+    connections: ConnectionTracker,
 }
 
 impl InboundPassthrough {
-    pub(crate) fn new(cfg: Config) -> InboundPassthrough {
-        InboundPassthrough { cfg }
+    pub(crate) fn new(
+        cfg: Config,
+        workloads: WorkloadInformation,
+        drain: Watch,
+        connections: ConnectionTracker,
+    ) -> InboundPassthrough {
+        InboundPassthrough {
+            cfg,
+            workloads,
+            drain,
+            connections,
+        }
     }
     pub(super) async fn run(self) {
         let tcp_listener: TcpListener = TcpListener::bind(self.cfg.inbound_plaintext_addr)
@@ -30,43 +45,92 @@ impl InboundPassthrough {
             tcp_listener.local_addr().unwrap()
         );
 
-        loop {
-            // Asynchronously wait for an inbound socket.
-            let socket = tcp_listener.accept().await;
-            match socket {
-                Ok((mut stream, remote)) => {
-                    info!("accepted inbound plaintext connection from {}", remote);
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::proxy_inbound_plaintext(&mut stream).await {
-                            warn!("plaintext proxying failed {}", e)
-                        }
-                    });
+        let drain = self.drain.clone();
+        let accept = async move {
+            loop {
+                // Asynchronously wait for an inbound socket.
+                let socket = tcp_listener.accept().await;
+                match socket {
+                    Ok((mut stream, remote)) => {
+                        debug!("accepted inbound plaintext connection from {}", remote);
+                        let guard = self.connections.track(self.drain.clone());
+                        let cfg = self.cfg.clone();
+                        let workloads = self.workloads.clone();
+                        let recorder = self.connections.recorder();
+                        tokio::spawn(async move {
+                            if let Err(e) = Self::proxy_inbound_plaintext(
+                                &cfg,
+                                &workloads,
+                                &recorder,
+                                &mut stream,
+                            )
+                            .await
+                            {
+                                warn!("plaintext proxying failed {}", e)
+                            }
+                            drop(guard);
+                        });
+                    }
+                    Err(e) => {
+                        crate::metrics::LISTENER_ERRORS
+                            .inc(vec![("listener", "inbound_passthrough".to_string())]);
+                        error!("Failed TCP handshake {}", e)
+                    }
                 }
-                Err(e) => error!("Failed TCP handshake {}", e),
+            }
+        };
+
+        // Stop accepting once we drain; open connections hold off the drain until they complete.
+        tokio::select! {
+            res = accept => { res }
+            _ = drain.signaled() => {
+                info!("inbound plaintext drained");
             }
         }
     }
 
-    async fn proxy_inbound_plaintext(inbound: &mut TcpStream) -> Result<(), Error> {
-        let orig = socket::orig_dst_addr(inbound).expect("must have original dst enabled");
-        let mut outbound = TcpStream::connect(orig).await?;
+    async fn proxy_inbound_plaintext(
+        cfg: &Config,
+        workloads: &WorkloadInformation,
+        recorder: &Recorder,
+        inbound: &mut TcpStream,
+    ) -> Result<(), Error> {
+        let source = inbound.peer_addr()?;
+        let orig = socket::orig_dst_addr(inbound, cfg.capture_mode)?;
+        let mut record =
+            ConnectionRecord::new(record::Direction::Inbound, Protocol::Tcp, source, orig);
+        record.source_workload = workloads
+            .fetch_workload(&super::to_canonical_ip(source))
+            .await;
+        record.destination_workload = workloads
+            .fetch_workload(&super::to_canonical_ip(orig))
+            .await;
+        record.destination_identity = record.destination_workload.as_ref().map(|w| w.identity());
+        // Plaintext traffic carries no trace context, so always starts a new trace.
+        let span = trace::start("inbound_passthrough", trace::SpanKind::Server, None);
 
-        let (mut ri, mut wi) = inbound.split();
-        let (mut ro, mut wo) = outbound.split();
-
-        let client_to_server = async {
-            io::copy(&mut ri, &mut wo).await?;
-            wo.shutdown().await
-        };
-
-        let server_to_client = async {
-            io::copy(&mut ro, &mut wi).await?;
-            wi.shutdown().await
+        let mut outbound = match super::dial(cfg, orig, Some(super::to_canonical_ip(source))).await
+        {
+            Ok(outbound) => outbound,
+            Err(e) => {
+                record.connect_failed(&e);
+                span.end(&record);
+                recorder.record(record);
+                return Err(e.into());
+            }
         };
+        recorder.opened(&record);
+        super::set_keepalive(cfg, inbound);
+        super::set_keepalive(cfg, &outbound);
 
-        tokio::try_join!(client_to_server, server_to_client)?;
+        let transfer = Transfer::new(cfg.idle_timeout);
+        let res = super::copy_tcp(inbound, &mut outbound, cfg.splice, &transfer).await;
+        record.finish(&transfer, &res);
+        span.end(&record);
+        recorder.record(record);
+        res?;
 
-        info!("proxy inbound plaintext complete");
+        debug!("proxy inbound plaintext complete");
         Ok(())
     }
 }
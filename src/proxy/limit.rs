@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+/// LimitError describes why a new connection was rejected.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("global connection limit of {0} reached")]
+    Global(usize),
+    #[error("connection limit of {0} per source reached")]
+    SourceConnections(usize),
+    #[error("connection rate limit of {0}/s per source exceeded")]
+    SourceRate(u32),
+}
+
+/// LimitStats reports the connections admitted and rejected by a ConnectionLimiter.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct LimitStats {
+    pub active: usize,
+    pub rejected_global: u64,
+    pub rejected_source_connections: u64,
+    pub rejected_source_rate: u64,
+}
+
+/// ConnectionLimiter enforces limits on concurrent connections, globally and per source, and on the
+/// rate of new connections per source. It is designed to be cheap to clone.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    max_connections: Option<usize>,
+    max_per_source: Option<usize>,
+    max_rate_per_source: Option<u32>,
+    state: Arc<Mutex<State>>,
+    counters: Arc<Counters>,
+}
+
+// How often acquire sweeps idle sources left behind with a depleted rate limit.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    sources: HashMap<IpAddr, Source>,
+    swept: Option<Instant>,
+}
+
+struct Source {
+    active: usize,
+    // Token bucket for new connections, refilled at max_rate_per_source per second.
+    tokens: f64,
+    refilled: Instant,
+}
+
+#[derive(Default)]
+struct Counters {
+    rejected_global: AtomicU64,
+    rejected_source_connections: AtomicU64,
+    rejected_source_rate: AtomicU64,
+}
+
+impl ConnectionLimiter {
+    pub fn new(cfg: &Config) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_connections: cfg.max_connections,
+            max_per_source: cfg.max_connections_per_source,
+            max_rate_per_source: cfg.max_connection_rate_per_source,
+            state: Default::default(),
+            counters: Default::default(),
+        }
+    }
+
+    /// acquire admits a new connection from source, if within limits. The connection counts against
+    /// the limits until the returned guard is dropped.
+    pub fn acquire(&self, source: IpAddr) -> Result<LimitGuard, LimitError> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if let Some(max) = self.max_connections {
+            if state.active >= max {
+                self.counters
+                    .rejected_global
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(LimitError::Global(max));
+            }
+        }
+        // Sources without connections are only kept until their rate limit recovers. Those released
+        // before that are dropped here, so sources that come and go do not grow the map unbounded.
+        let now = Instant::now();
+        if state
+            .swept
+            .map_or(true, |swept| now.duration_since(swept) >= SWEEP_INTERVAL)
+        {
+            let rate = self.max_rate_per_source;
+            state
+                .sources
+                .retain(|_, src| src.active > 0 || !src.recovered(rate));
+            state.swept = Some(now);
+        }
+        let capacity = self.max_rate_per_source.map(f64::from).unwrap_or_default();
+        let src = state.sources.entry(source).or_insert_with(|| Source {
+            active: 0,
+            tokens: capacity,
+            refilled: Instant::now(),
+        });
+        if let Some(max) = self.max_per_source {
+            if src.active >= max {
+                self.counters
+                    .rejected_source_connections
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(LimitError::SourceConnections(max));
+            }
+        }
+        if let Some(rate) = self.max_rate_per_source {
+            src.refill(rate);
+            if src.tokens < 1.0 {
+                self.counters
+                    .rejected_source_rate
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(LimitError::SourceRate(rate));
+            }
+            src.tokens -= 1.0;
+        }
+        src.active += 1;
+        state.active += 1;
+        Ok(LimitGuard {
+            source,
+            limiter: self.clone(),
+        })
+    }
+
+    pub fn stats(&self) -> LimitStats {
+        LimitStats {
+            active: self.state.lock().unwrap().active,
+            rejected_global: self.counters.rejected_global.load(Ordering::Relaxed),
+            rejected_source_connections: self
+                .counters
+                .rejected_source_connections
+                .load(Ordering::Relaxed),
+            rejected_source_rate: self.counters.rejected_source_rate.load(Ordering::Relaxed),
+        }
+    }
+
+    fn release(&self, source: IpAddr) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.active -= 1;
+        if let Some(src) = state.sources.get_mut(&source) {
+            src.active -= 1;
+            // Forget idle sources once their rate limit has fully recovered; acquire sweeps the rest.
+            if src.active == 0 && src.recovered(self.max_rate_per_source) {
+                state.sources.remove(&source);
+            }
+        }
+    }
+}
+
+impl Source {
+    fn refill(&mut self, rate: u32) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(rate)).min(f64::from(rate));
+        self.refilled = now;
+    }
+
+    /// recovered determines if the source's rate limit is back to a full bucket, so that forgetting
+    /// the source and recreating it later makes no difference.
+    fn recovered(&mut self, rate: Option<u32>) -> bool {
+        match rate {
+            Some(rate) => {
+                self.refill(rate);
+                self.tokens >= f64::from(rate)
+            }
+            None => true,
+        }
+    }
+}
+
+/// LimitGuard holds a connection's place against the limits until it is dropped.
+pub struct LimitGuard {
+    source: IpAddr,
+    limiter: ConnectionLimiter,
+}
+
+impl Drop for LimitGuard {
+    fn drop(&mut self) {
+        self.limiter.release(self.source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(
+        max_connections: Option<usize>,
+        max_connections_per_source: Option<usize>,
+        max_connection_rate_per_source: Option<u32>,
+    ) -> ConnectionLimiter {
+        ConnectionLimiter::new(&Config {
+            max_connections,
+            max_connections_per_source,
+            max_connection_rate_per_source,
+            ..Default::default()
+        })
+    }
+
+    const SRC_A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const SRC_B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn per_source_connections() {
+        let limiter = limiter(None, Some(2), None);
+        let a1 = limiter.acquire(SRC_A).unwrap();
+        let _a2 = limiter.acquire(SRC_A).unwrap();
+        assert_eq!(
+            limiter.acquire(SRC_A).err(),
+            Some(LimitError::SourceConnections(2))
+        );
+        assert!(limiter.acquire(SRC_B).is_ok(), "other sources unaffected");
+
+        drop(a1);
+        assert!(limiter.acquire(SRC_A).is_ok(), "released on drop");
+        assert_eq!(limiter.stats().rejected_source_connections, 1);
+    }
+
+    #[test]
+    fn global_connections() {
+        let limiter = limiter(Some(1), None, None);
+        let a = limiter.acquire(SRC_A).unwrap();
+        assert_eq!(limiter.acquire(SRC_B).err(), Some(LimitError::Global(1)));
+        drop(a);
+        assert!(limiter.acquire(SRC_B).is_ok());
+        assert_eq!(limiter.stats().rejected_global, 1);
+    }
+
+    #[test]
+    fn per_source_rate() {
+        let limiter = limiter(None, None, Some(2));
+        assert!(limiter.acquire(SRC_A).is_ok());
+        assert!(limiter.acquire(SRC_A).is_ok());
+        assert_eq!(
+            limiter.acquire(SRC_A).err(),
+            Some(LimitError::SourceRate(2))
+        );
+        assert!(limiter.acquire(SRC_B).is_ok(), "other sources unaffected");
+        assert_eq!(limiter.stats().rejected_source_rate, 1);
+        assert_eq!(limiter.stats().active, 0);
+    }
+
+    #[test]
+    fn idle_sources_swept() {
+        let limiter = limiter(None, None, Some(10));
+        // Released before the rate limit recovered, so the source is kept for now.
+        drop(limiter.acquire(SRC_A).unwrap());
+        assert!(limiter.state.lock().unwrap().sources.contains_key(&SRC_A));
+
+        std::thread::sleep(SWEEP_INTERVAL + Duration::from_millis(100));
+        let _b = limiter.acquire(SRC_B).unwrap();
+        let state = limiter.state.lock().unwrap();
+        assert!(!state.sources.contains_key(&SRC_A), "swept once recovered");
+        assert!(state.sources.contains_key(&SRC_B));
+    }
+}
@@ -0,0 +1,197 @@
+use crate::identity::Identity;
+use crate::metrics::{
+    Labels, TCP_CONNECTIONS_CLOSED, TCP_CONNECTIONS_OPENED, TCP_RECEIVED_BYTES, TCP_SENT_BYTES,
+};
+use crate::proxy::{CloseReason, ConnectionRecord, Direction, PeerMetadata, RecordSink};
+use crate::workload::Protocol;
+
+/// TcpMetrics maintains Istio's standard TCP metrics from connection records. Bytes are counted as
+/// open connections report progress, without response flags as the connection has not closed yet;
+/// the remainder is counted with the flags the connection closed with.
+pub struct TcpMetrics;
+
+impl RecordSink for TcpMetrics {
+    fn opened(&self, record: &ConnectionRecord) {
+        TCP_CONNECTIONS_OPENED.inc(labels(record, "-"));
+    }
+
+    fn progress(&self, record: &ConnectionRecord, sent: u64, received: u64) {
+        let labels = labels(record, "-");
+        TCP_SENT_BYTES.inc_by(labels.clone(), sent);
+        TCP_RECEIVED_BYTES.inc_by(labels, received);
+    }
+
+    fn record(&self, record: &ConnectionRecord) {
+        let flags = record.close_reason.response_flags();
+        if record.close_reason == CloseReason::ConnectFailed {
+            // Connections that failed to connect are never reported as opened; count them here so
+            // opened and closed connections add up.
+            TCP_CONNECTIONS_OPENED.inc(labels(record, flags));
+        }
+        let labels = labels(record, flags);
+        let (sent, received) = record.unreported_bytes();
+        TCP_SENT_BYTES.inc_by(labels.clone(), sent);
+        TCP_RECEIVED_BYTES.inc_by(labels.clone(), received);
+        TCP_CONNECTIONS_CLOSED.inc(labels);
+    }
+}
+
+fn labels(record: &ConnectionRecord, response_flags: &str) -> Labels {
+    fn peer(p: &Option<PeerMetadata>, f: impl Fn(&PeerMetadata) -> &str) -> String {
+        match p.as_ref().map(f) {
+            Some(v) if !v.is_empty() => v.to_string(),
+            _ => "unknown".to_string(),
+        }
+    }
+    fn principal(id: &Option<Identity>) -> String {
+        id.as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+    let (src, dst) = (record.source_peer(), record.destination_peer());
+    vec![
+        (
+            "reporter",
+            match record.direction {
+                Direction::Inbound => "destination",
+                Direction::Outbound => "source",
+            }
+            .to_string(),
+        ),
+        ("source_workload", peer(&src, |p| p.workload_name.as_str())),
+        (
+            "source_workload_namespace",
+            peer(&src, |p| p.namespace.as_str()),
+        ),
+        ("source_principal", principal(&record.source_identity)),
+        (
+            "source_canonical_service",
+            peer(&src, |p| p.canonical_name.as_str()),
+        ),
+        (
+            "source_canonical_revision",
+            peer(&src, |p| p.canonical_revision.as_str()),
+        ),
+        ("source_cluster", peer(&src, |p| p.cluster_id.as_str())),
+        (
+            "destination_workload",
+            peer(&dst, |p| p.workload_name.as_str()),
+        ),
+        (
+            "destination_workload_namespace",
+            peer(&dst, |p| p.namespace.as_str()),
+        ),
+        (
+            "destination_principal",
+            principal(&record.destination_identity),
+        ),
+        (
+            "destination_canonical_service",
+            peer(&dst, |p| p.canonical_name.as_str()),
+        ),
+        (
+            "destination_canonical_revision",
+            peer(&dst, |p| p.canonical_revision.as_str()),
+        ),
+        ("destination_cluster", peer(&dst, |p| p.cluster_id.as_str())),
+        ("request_protocol", "tcp".to_string()),
+        ("response_flags", response_flags.to_string()),
+        (
+            "connection_security_policy",
+            match record.protocol {
+                Protocol::Hbone => "mutual_tls",
+                Protocol::Tcp => "unknown",
+            }
+            .to_string(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::proxy::transfer::Flow;
+    use crate::proxy::{Recorder, Transfer};
+
+    #[test]
+    fn tcp_metrics() {
+        // Metrics are global; use a destination unique to this test.
+        let mut record = ConnectionRecord::new(
+            Direction::Inbound,
+            Protocol::Hbone,
+            "10.0.0.1:41000".parse().unwrap(),
+            "10.0.0.2:8080".parse().unwrap(),
+        );
+        record.destination_identity = Some(Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "tcp-metrics-test".to_string(),
+            service_account: "default".to_string(),
+        });
+        let sink = TcpMetrics;
+        sink.opened(&record);
+        let opened = labels(&record, "-");
+        assert_eq!(TCP_CONNECTIONS_OPENED.get(&opened), 1);
+
+        let transfer = Transfer::new(std::time::Duration::MAX);
+        record.finish(&transfer, &Ok(()));
+        record.bytes_sent = 10;
+        record.bytes_received = 20;
+        sink.record(&record);
+        assert_eq!(TCP_CONNECTIONS_CLOSED.get(&opened), 1);
+        assert_eq!(TCP_SENT_BYTES.get(&opened), 10);
+        assert_eq!(TCP_RECEIVED_BYTES.get(&opened), 20);
+        assert!(opened.contains(&("reporter", "destination".to_string())));
+        assert!(opened.contains(&("source_workload", "unknown".to_string())));
+
+        // Metadata exchanged with the peer takes precedence in its labels.
+        record.source_metadata = Some(PeerMetadata {
+            cluster_id: "remote".to_string(),
+            workload_name: "sleep".to_string(),
+            ..Default::default()
+        });
+        let peer_labels = labels(&record, "-");
+        assert!(peer_labels.contains(&("source_workload", "sleep".to_string())));
+        assert!(peer_labels.contains(&("source_cluster", "remote".to_string())));
+
+        record.connect_failed("refused");
+        sink.record(&record);
+        let failed = labels(&record, "UF");
+        assert_eq!(TCP_CONNECTIONS_OPENED.get(&failed), 1);
+        assert_eq!(TCP_CONNECTIONS_CLOSED.get(&failed), 1);
+    }
+
+    #[test]
+    fn tcp_metrics_progress() {
+        let mut record = ConnectionRecord::new(
+            Direction::Outbound,
+            Protocol::Tcp,
+            "10.0.0.1:41000".parse().unwrap(),
+            "10.0.0.3:8080".parse().unwrap(),
+        );
+        record.destination_identity = Some(Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "tcp-metrics-progress-test".to_string(),
+            service_account: "default".to_string(),
+        });
+        let recorder = Recorder::new(vec![Arc::new(TcpMetrics) as Arc<dyn RecordSink>]);
+        let labels = labels(&record, "-");
+        let transfer = Transfer::new(std::time::Duration::MAX);
+
+        // Bytes count while the connection is still open.
+        transfer.add(Flow::Sent, 10);
+        recorder.progress(&mut record, &transfer);
+        assert_eq!(TCP_SENT_BYTES.get(&labels), 10);
+        recorder.progress(&mut record, &transfer);
+        assert_eq!(TCP_SENT_BYTES.get(&labels), 10, "only deltas are reported");
+
+        // Only the remainder is counted once it closes.
+        transfer.add(Flow::Sent, 5);
+        transfer.add(Flow::Received, 7);
+        record.finish(&transfer, &Ok(()));
+        recorder.record(record);
+        assert_eq!(TCP_SENT_BYTES.get(&labels), 15);
+        assert_eq!(TCP_RECEIVED_BYTES.get(&labels), 7);
+    }
+}
@@ -2,25 +2,53 @@ use boring::error::ErrorStack;
 use drain::Watch;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use tokio::net::TcpStream;
-use tracing::info;
+use tracing::{debug, info};
 
 use inbound::Inbound;
 
+pub use crate::proxy::access_log::{
+    AccessLog, AccessLogConfig, AccessLogFormat, Template as AccessLogTemplate,
+    DEFAULT_TEXT_FORMAT as DEFAULT_ACCESS_LOG_FORMAT,
+};
+pub use crate::proxy::baggage::PeerMetadata;
 use crate::proxy::inbound_passthrough::InboundPassthrough;
+pub use crate::proxy::limit::{ConnectionLimiter, LimitError, LimitStats};
+pub use crate::proxy::metrics::TcpMetrics;
 use crate::proxy::outbound::Outbound;
+pub use crate::proxy::outbound::UnknownSourcePolicy;
+pub use crate::proxy::pool::{Pool, PoolStats};
+pub use crate::proxy::record::{CloseReason, ConnectionRecord, Direction, RecordSink, Recorder};
+use crate::proxy::socks5::Socks5;
+use crate::socket::{self, CaptureMode};
 use crate::workload::WorkloadInformation;
 use crate::{config, identity, tls};
 
+mod access_log;
+mod baggage;
 mod inbound;
 mod inbound_passthrough;
+mod limit;
+mod metrics;
 mod outbound;
+mod pool;
+mod record;
+mod socks5;
+#[cfg(target_os = "linux")]
+mod splice;
+mod transfer;
+
+pub use transfer::Transfer;
 
 pub struct Proxy {
     inbound: Inbound,
     inbound_passthrough: InboundPassthrough,
     outbound: Outbound,
+    socks5: Option<Socks5>,
+    connections: ConnectionTracker,
 }
 
 impl Proxy {
@@ -28,25 +56,86 @@ impl Proxy {
         cfg: config::Config,
         workloads: WorkloadInformation,
         secret_manager: identity::SecretManager,
+        recorder: Recorder,
         drain: Watch,
     ) -> Result<Proxy, Error> {
         // We setup all the listeners first so we can capture any errors that should block startup
-        let inbound_passthrough = InboundPassthrough::new(cfg.clone());
-        let inbound = Inbound::new(cfg.clone(), workloads.clone(), secret_manager.clone()).await?;
-        let outbound = Outbound::new(cfg.clone(), secret_manager, workloads, drain).await?;
+        let connections = ConnectionTracker::new(recorder);
+        let limiter = ConnectionLimiter::new(&cfg);
+        let inbound_passthrough = InboundPassthrough::new(
+            cfg.clone(),
+            workloads.clone(),
+            drain.clone(),
+            connections.clone(),
+        );
+        // Connections forwarded to native HBONE workloads are limited separately, so they cannot
+        // exhaust the limits of outbound connections from the same source.
+        let inbound = Inbound::new(
+            cfg.clone(),
+            workloads.clone(),
+            secret_manager.clone(),
+            drain.clone(),
+            connections.clone(),
+            ConnectionLimiter::new(&cfg),
+        )
+        .await?;
+        let outbound = Outbound::new(
+            cfg.clone(),
+            secret_manager,
+            workloads.clone(),
+            drain.clone(),
+            connections.clone(),
+            limiter,
+        )
+        .await?;
+        let socks5 = match cfg.socks5_addr {
+            Some(addr) => Some(
+                Socks5::new(
+                    cfg.clone(),
+                    addr,
+                    outbound.pool(),
+                    workloads,
+                    drain,
+                    connections.clone(),
+                    outbound.limiter(),
+                )
+                .await?,
+            ),
+            None => None,
+        };
         Ok(Proxy {
             inbound,
             inbound_passthrough,
             outbound,
+            socks5,
+            connections,
         })
     }
 
+    /// limiter returns the limiter applied to new outbound connections.
+    pub fn limiter(&self) -> ConnectionLimiter {
+        self.outbound.limiter()
+    }
+
+    /// connections returns the tracker of connections open on all listeners.
+    pub fn connections(&self) -> ConnectionTracker {
+        self.connections.clone()
+    }
+
+    /// pool returns the pool of outbound HBONE connections.
+    pub fn pool(&self) -> Pool {
+        self.outbound.pool()
+    }
+
     pub async fn run(self) {
-        let tasks = vec![
+        let mut tasks = vec![
             tokio::spawn(self.inbound_passthrough.run()),
             tokio::spawn(self.inbound.run()),
             tokio::spawn(self.outbound.run()),
         ];
+        if let Some(socks5) = self.socks5 {
+            tasks.push(tokio::spawn(socks5.run()));
+        }
 
         futures::future::join_all(tasks).await;
     }
@@ -77,26 +166,169 @@ pub enum Error {
 
     #[error("identity error: {0}")]
     Identity(#[from] identity::Error),
+
+    #[error("connection to {0} timed out")]
+    ConnectTimeout(SocketAddr),
+
+    #[error("connection idle for {0:?}")]
+    IdleTimeout(std::time::Duration),
+
+    #[error("upstream rejected connection with status {0}")]
+    HttpStatus(hyper::StatusCode),
+
+    #[error("upstream response headers of {0} bytes exceed the limit")]
+    HeadersTooLarge(usize),
+
+    #[error("local connection rejected: {0}")]
+    LocalRejected(String),
+
+    #[error("unknown source: {0}")]
+    UnknownSource(IpAddr),
+
+    #[error("peer presented identity {peer:?}, expected {expected}")]
+    PeerIdentityMismatch {
+        expected: identity::Identity,
+        peer: Option<identity::Identity>,
+    },
+
+    #[error("socks5 handshake failed: {0}")]
+    Socks5(String),
+}
+
+/// dial connects to addr, on behalf of a client at source if the connection carries its traffic.
+/// With TPROXY capture such connections are made from the client's own address, so the destination
+/// sees the real client.
+pub(super) async fn dial(
+    cfg: &config::Config,
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+) -> io::Result<TcpStream> {
+    let dialer = socket::Dialer {
+        mark: cfg.socket_mark,
+        transparent: cfg.capture_mode == CaptureMode::Tproxy,
+    };
+    dialer.connect(addr, source).await
+}
+
+/// connect_timeout dials addr like dial, giving up after the configured connect timeout.
+pub(super) async fn connect_timeout(
+    cfg: &config::Config,
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+) -> Result<TcpStream, Error> {
+    tokio::time::timeout(cfg.connect_timeout, dial(cfg, addr, source))
+        .await
+        .map_err(|_| Error::ConnectTimeout(addr))?
+        .map_err(Error::Io)
+}
+
+/// header_size returns the size of headers as counted against HTTP/2's SETTINGS_MAX_HEADER_LIST_SIZE:
+/// the length of each name and value, plus 32 bytes of overhead per field.
+pub(super) fn header_size(headers: &hyper::HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 32)
+        .sum()
+}
+
+/// forwarded_for builds a Forwarded header value identifying the client address ip.
+pub(super) fn forwarded_for(ip: IpAddr) -> hyper::header::HeaderValue {
+    let value = match ip {
+        IpAddr::V4(ip) => format!("for={ip}"),
+        IpAddr::V6(ip) => format!("for=\"[{ip}]\""),
+    };
+    hyper::header::HeaderValue::from_str(&value).expect("addresses are valid header values")
+}
+
+/// parse_forwarded_for returns the client address in a Forwarded header built by forwarded_for.
+pub(super) fn parse_forwarded_for(headers: &hyper::HeaderMap) -> Option<IpAddr> {
+    let value = headers.get(hyper::header::FORWARDED)?.to_str().ok()?;
+    let ip = value.trim().strip_prefix("for=")?;
+    ip.trim_matches('"')
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// ConnectionTracker counts the connections open across all listeners, and records them once they
+/// close.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionTracker {
+    active: Arc<AtomicUsize>,
+    recorder: Recorder,
+}
+
+impl ConnectionTracker {
+    pub fn new(recorder: Recorder) -> ConnectionTracker {
+        ConnectionTracker {
+            active: Default::default(),
+            recorder,
+        }
+    }
+
+    /// track marks a connection as open until the returned guard is dropped. The guard holds a
+    /// drain watch, so a graceful shutdown waits for the connection to complete.
+    pub(super) fn track(&self, drain: Watch) -> ConnectionGuard {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            active: self.active.clone(),
+            _drain: drain,
+        }
+    }
+
+    /// active returns the number of open connections.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// recorder returns the recorder closed connections are reported to.
+    pub fn recorder(&self) -> Recorder {
+        self.recorder.clone()
+    }
+}
+
+pub(super) struct ConnectionGuard {
+    active: Arc<AtomicUsize>,
+    _drain: Watch,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 // TLS record size max is 16k. But we also have a H2 frame header, so leave a bit of room for that.
 const HBONE_BUFFER_SIZE: usize = 16_384 - 64;
 
-pub async fn copy_hbone(
+/// copy_hbone proxies data in both directions between a downstream and an upstream, one of which is
+/// an HBONE tunnel, until both sides are closed or the transfer is idle for too long. Data sent
+/// from downstream to upstream, and back, is accounted for in transfer.
+pub async fn copy_hbone<D, U>(
     desc: &str,
-    upgraded: &mut hyper::upgrade::Upgraded,
-    stream: &mut TcpStream,
-) -> Result<(), std::io::Error> {
+    downstream: &mut D,
+    upstream: &mut U,
+    transfer: &Transfer,
+) -> Result<(), Error>
+where
+    D: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    U: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
     use tokio::io::AsyncWriteExt;
-    let (mut ri, mut wi) = tokio::io::split(upgraded);
-    let (mut ro, mut wo) = stream.split();
+    let (ri, mut wi) = tokio::io::split(downstream);
+    let (ro, mut wo) = tokio::io::split(upstream);
+    let (mut ri, mut ro) = (
+        transfer.reader(ri, transfer::Flow::Sent),
+        transfer.reader(ro, transfer::Flow::Received),
+    );
 
     let client_to_server = async {
         let mut ri = tokio::io::BufReader::with_capacity(HBONE_BUFFER_SIZE, &mut ri);
         let mut wo = tokio::io::BufWriter::with_capacity(HBONE_BUFFER_SIZE, &mut wo);
         let res = tokio::io::copy(&mut ri, &mut wo).await;
-        info!(?res, ?desc, "hbone -> tcp");
-        res.expect("");
+        debug!(?res, ?desc, "downstream -> upstream");
+        res?;
         wo.shutdown().await
     };
 
@@ -104,11 +336,13 @@ pub async fn copy_hbone(
         let mut ro = tokio::io::BufReader::with_capacity(HBONE_BUFFER_SIZE, &mut ro);
         let mut wi = tokio::io::BufWriter::with_capacity(HBONE_BUFFER_SIZE, &mut wi);
         let res = tokio::io::copy(&mut ro, &mut wi).await;
-        info!(?res, ?desc, "tcp -> hbone");
+        debug!(?res, ?desc, "upstream -> downstream");
         wi.shutdown().await
     };
 
-    tokio::try_join!(client_to_server, server_to_client).map(|_| ())
+    transfer
+        .run(async { tokio::try_join!(client_to_server, server_to_client).map(|_| ()) })
+        .await
     // TODO: Buffered may be faster, but couldn't get around the "WriteZero" errors
     // let (ri, mut wi) = tokio::io::split(upgraded);
     // let (ro, mut wo) = stream.split();
@@ -133,6 +367,70 @@ pub async fn copy_hbone(
     // tokio::try_join!(client_to_server, server_to_client).map(|_| ())
 }
 
+/// copy_tcp proxies data in both directions between two TCP streams until both sides are closed,
+/// or the transfer is idle for too long. Data sent from downstream to upstream, and back, is
+/// accounted for in transfer.
+/// If use_splice is set, data is moved with splice(2) where supported, avoiding copies through
+/// userspace; otherwise, or if splice is unavailable, data is copied through buffers.
+pub async fn copy_tcp(
+    downstream: &mut TcpStream,
+    upstream: &mut TcpStream,
+    use_splice: bool,
+    transfer: &Transfer,
+) -> Result<(), Error> {
+    use transfer::Flow;
+
+    #[cfg(target_os = "linux")]
+    if use_splice {
+        match (splice::Pipe::new(), splice::Pipe::new()) {
+            (Ok(p1), Ok(p2)) => {
+                let client_to_server = splice::copy(downstream, upstream, p1, transfer, Flow::Sent);
+                let server_to_client =
+                    splice::copy(upstream, downstream, p2, transfer, Flow::Received);
+                return transfer
+                    .run(async { tokio::try_join!(client_to_server, server_to_client).map(|_| ()) })
+                    .await;
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                info!("splice unavailable, falling back to userspace copy: {}", e)
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = use_splice;
+
+    use tokio::io::AsyncWriteExt;
+    let (ri, mut wi) = downstream.split();
+    let (ro, mut wo) = upstream.split();
+    let (mut ri, mut ro) = (
+        transfer.reader(ri, Flow::Sent),
+        transfer.reader(ro, Flow::Received),
+    );
+
+    let client_to_server = async {
+        tokio::io::copy(&mut ri, &mut wo).await?;
+        wo.shutdown().await
+    };
+
+    let server_to_client = async {
+        tokio::io::copy(&mut ro, &mut wi).await?;
+        wi.shutdown().await
+    };
+
+    transfer
+        .run(async { tokio::try_join!(client_to_server, server_to_client).map(|_| ()) })
+        .await
+}
+
+/// set_keepalive applies the configured TCP keepalive to a proxied socket.
+fn set_keepalive<T: std::os::unix::io::AsRawFd>(cfg: &config::Config, sock: &T) {
+    if let Some(keepalive) = &cfg.keepalive {
+        if let Err(e) = crate::socket::set_keepalive(sock, keepalive) {
+            debug!("failed to set TCP keepalive: {}", e);
+        }
+    }
+}
+
 fn to_canonical_ip(ip: SocketAddr) -> IpAddr {
     // another match has to be used for IPv4 and IPv6 support
     // @zhlsunshine TODO: to_canonical() should be used when it becomes stable a function in Rust
@@ -146,3 +444,89 @@ fn to_canonical_ip(ip: SocketAddr) -> IpAddr {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn copy_tcp_modes() {
+        for use_splice in [true, false] {
+            // Upstream echoes everything back until the client closes its side.
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream_addr = upstream.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await.unwrap();
+            });
+
+            let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = proxy.local_addr().unwrap();
+            let proxied = tokio::spawn(async move {
+                let (mut downstream, _) = proxy.accept().await.unwrap();
+                let mut upstream = TcpStream::connect(upstream_addr).await.unwrap();
+                let transfer = Transfer::new(Duration::from_secs(10));
+                copy_tcp(&mut downstream, &mut upstream, use_splice, &transfer)
+                    .await
+                    .unwrap();
+                transfer
+            });
+
+            let payload = vec![7u8; 1024 * 1024];
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let (mut r, mut w) = client.split();
+            let write = async {
+                w.write_all(&payload).await.unwrap();
+                w.shutdown().await.unwrap();
+            };
+            let mut echoed = Vec::new();
+            let read = r.read_to_end(&mut echoed);
+            let (_, read) = tokio::join!(write, read);
+            read.unwrap();
+            assert_eq!(echoed, payload, "splice: {use_splice}");
+            let transfer = proxied.await.unwrap();
+            assert_eq!(transfer.sent(), payload.len() as u64);
+            assert_eq!(transfer.received(), payload.len() as u64);
+        }
+    }
+
+    #[test]
+    fn forwarded() {
+        for ip in ["10.0.0.1", "fd00::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut headers = hyper::HeaderMap::new();
+            headers.insert(hyper::header::FORWARDED, forwarded_for(ip));
+            assert_eq!(parse_forwarded_for(&headers), Some(ip));
+        }
+        let mut headers = hyper::HeaderMap::new();
+        assert_eq!(parse_forwarded_for(&headers), None);
+        headers.insert(hyper::header::FORWARDED, "by=10.0.0.1".parse().unwrap());
+        assert_eq!(parse_forwarded_for(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn connection_tracker_holds_drain() {
+        let (signal, watch) = drain::channel();
+        let tracker = ConnectionTracker::default();
+        let guard = tracker.track(watch.clone());
+        drop(watch);
+        assert_eq!(tracker.active(), 1);
+
+        let mut drain = tokio::spawn(signal.drain());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut drain)
+                .await
+                .is_err(),
+            "drain must wait for open connections"
+        );
+        drop(guard);
+        drain.await.unwrap();
+        assert_eq!(tracker.active(), 0);
+    }
+}
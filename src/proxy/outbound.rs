@@ -1,22 +1,31 @@
+use std::fmt;
 use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 
-use boring::ssl::ConnectConfiguration;
 use drain::Watch;
-use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tracing::{debug, error, info, warn};
 
 use crate::config::Config;
-use crate::proxy::Error;
-use crate::workload::{Protocol, Workload, WorkloadInformation};
+use crate::identity::Identity;
+use crate::metrics::UNKNOWN_SOURCE_CONNECTIONS;
+use crate::proxy::inbound::{Inbound, LocalConnection};
+use crate::proxy::record::{self, ConnectionRecord};
+use crate::proxy::{
+    baggage, pool, ConnectionLimiter, ConnectionTracker, Error, PeerMetadata, Recorder, Transfer,
+};
+use crate::telemetry::trace::{self, TraceContext};
+use crate::workload::{lb, Protocol, Workload, WorkloadInformation};
 use crate::{identity, socket};
 
 pub struct Outbound {
     cfg: Config,
-    cert_manager: identity::SecretManager,
+    pool: pool::Pool,
     workloads: WorkloadInformation,
     listener: TcpListener,
     drain: Watch,
+    connections: ConnectionTracker,
+    limiter: ConnectionLimiter,
 }
 
 impl Outbound {
@@ -25,6 +34,8 @@ impl Outbound {
         cert_manager: identity::SecretManager,
         workloads: WorkloadInformation,
         drain: Watch,
+        connections: ConnectionTracker,
+        limiter: ConnectionLimiter,
     ) -> Result<Outbound, Error> {
         let listener: TcpListener = TcpListener::bind(cfg.outbound_addr)
             .await
@@ -34,194 +45,464 @@ impl Outbound {
             _ => info!("running with transparent mode"),
         };
 
+        let pool = pool::Pool::new(cfg.clone(), cert_manager);
         Ok(Outbound {
             cfg,
-            cert_manager,
+            pool,
             workloads,
             listener,
             drain,
+            connections,
+            limiter,
         })
     }
 
+    pub(super) fn pool(&self) -> pool::Pool {
+        self.pool.clone()
+    }
+
+    pub(super) fn limiter(&self) -> ConnectionLimiter {
+        self.limiter.clone()
+    }
+
     pub(super) async fn run(self) {
         let addr = self.listener.local_addr().unwrap();
         info!("outbound listener established {}", addr);
+        tokio::spawn(self.pool.clone().reap(self.drain.clone()));
 
+        let drain = self.drain.clone();
         let accept = async move {
             loop {
                 // Asynchronously wait for an inbound socket.
                 let socket = self.listener.accept().await;
                 match socket {
                     Ok((stream, remote)) => {
-                        info!("accepted outbound connection from {}", remote);
+                        debug!("accepted outbound connection from {}", remote);
+                        let limit_guard = match self.limiter.acquire(super::to_canonical_ip(remote))
+                        {
+                            Ok(guard) => guard,
+                            Err(e) => {
+                                // Dropping the stream closes the connection immediately.
+                                warn!("rejecting outbound connection from {}: {}", remote, e);
+                                continue;
+                            }
+                        };
                         let cfg = self.cfg.clone();
+                        let guard = self.connections.track(self.drain.clone());
                         let oc = OutboundConnection {
-                            cert_manager: self.cert_manager.clone(),
+                            pool: self.pool.clone(),
                             workloads: self.workloads.clone(),
+                            recorder: self.connections.recorder(),
                             cfg,
                         };
                         tokio::spawn(async move {
                             let res = oc.proxy(stream).await;
                             match res {
-                                Ok(_) => info!("outbound proxy complete"),
+                                Ok(_) => debug!("outbound proxy complete"),
                                 Err(ref e) => warn!("outbound proxy failed: {}", e),
                             };
+                            drop(guard);
+                            drop(limit_guard);
                         });
                     }
-                    Err(e) => error!("Failed TCP handshake {}", e),
+                    Err(e) => {
+                        crate::metrics::LISTENER_ERRORS
+                            .inc(vec![("listener", "outbound".to_string())]);
+                        error!("Failed TCP handshake {}", e)
+                    }
                 }
             }
         };
 
         // Stop accepting once we drain.
-        // Note: we are *not* waiting for all connections to be closed. In the future, we may consider
-        // this, but will need some timeout period, as we have no back-pressure mechanism on connections.
+        // Open connections hold a drain watch, so shutdown waits for them to complete, up to the
+        // termination grace period.
         tokio::select! {
             res = accept => { res }
-            _ = self.drain.signaled() => {
+            _ = drain.signaled() => {
                 info!("outbound drained");
             }
         }
     }
 }
 
-struct OutboundConnection {
-    cert_manager: identity::SecretManager,
-    workloads: WorkloadInformation,
+pub(super) struct OutboundConnection {
+    pub(super) pool: pool::Pool,
+    pub(super) workloads: WorkloadInformation,
+    pub(super) recorder: Recorder,
     // TODO: Config may be excessively large, maybe we store a scoped OutboundConfig intended for cloning.
-    cfg: Config,
+    pub(super) cfg: Config,
 }
 
 impl OutboundConnection {
-    async fn proxy(&self, mut stream: TcpStream) -> Result<(), Error> {
-        let remote_addr =
-            super::to_canonical_ip(stream.peer_addr().expect("must receive peer addr"));
-        let orig = socket::orig_dst_addr(&stream).expect("must have original dst enabled");
-        let req = self.build_request(remote_addr, orig).await;
-        debug!("request from {} to {}", req.source.name, orig);
+    async fn proxy(&self, stream: TcpStream) -> Result<(), Error> {
+        let orig = socket::orig_dst_addr(&stream, self.cfg.capture_mode)?;
+        self.proxy_to_target(stream, orig).await
+    }
+
+    /// proxy_to_target proxies stream to orig, the destination the client intended to reach.
+    /// The connection is recorded once it closes, whether or not it succeeded.
+    pub(super) async fn proxy_to_target(
+        &self,
+        mut stream: TcpStream,
+        orig: SocketAddr,
+    ) -> Result<(), Error> {
+        let source = stream.peer_addr().expect("must receive peer addr");
+        let established = self.connect_target(source, orig).await?;
+        self.proxy_established(established, &mut stream).await
+    }
+
+    /// connect_target establishes the upstream connection for a client at source to orig, without
+    /// proxying any data yet. A failed connection is recorded before the error is returned.
+    pub(super) async fn connect_target(
+        &self,
+        source: SocketAddr,
+        orig: SocketAddr,
+    ) -> Result<Established, Error> {
+        let mut record =
+            ConnectionRecord::new(record::Direction::Outbound, Protocol::Tcp, source, orig);
+        let span = trace::start("outbound", trace::SpanKind::Client, None);
+        match self
+            .connect_with_retries(
+                super::to_canonical_ip(source),
+                orig,
+                span.context().as_ref(),
+                &mut record,
+            )
+            .await
+        {
+            Ok((upstream, lb_guard)) => {
+                self.recorder.opened(&record);
+                Ok(Established {
+                    upstream,
+                    record,
+                    span,
+                    _lb_guard: lb_guard,
+                })
+            }
+            Err(e) => {
+                record.connect_failed(&e);
+                span.end(&record);
+                self.recorder.record(record);
+                Err(e)
+            }
+        }
+    }
+
+    /// proxy_established proxies stream over an upstream connection from connect_target, recording
+    /// the connection once it closes.
+    pub(super) async fn proxy_established(
+        &self,
+        established: Established,
+        stream: &mut TcpStream,
+    ) -> Result<(), Error> {
+        super::set_keepalive(&self.cfg, stream);
+        let Established {
+            upstream,
+            mut record,
+            span,
+            _lb_guard,
+        } = established;
+        let transfer = Transfer::new(self.cfg.idle_timeout);
+        let res = self
+            .recorder
+            .track(
+                &mut record,
+                &transfer,
+                self.proxy_to(upstream, stream, &transfer),
+            )
+            .await;
+        record.finish(&transfer, &res);
+        span.end(&record);
+        self.recorder.record(record);
+        res
+    }
+
+    /// abandon closes an upstream connection from connect_target that will not be proxied, as the
+    /// client went away in the meantime, recording it with err.
+    pub(super) fn abandon(&self, established: Established, err: Error) -> Result<(), Error> {
+        let Established {
+            upstream,
+            mut record,
+            span,
+            _lb_guard,
+        } = established;
+        let transfer = Transfer::new(self.cfg.idle_timeout);
+        let res = Err(err);
+        if let Connected::Local(_, local) = upstream {
+            local.finish(&transfer, &res);
+        }
+        record.finish(&transfer, &res);
+        span.end(&record);
+        self.recorder.record(record);
+        res
+    }
+
+    /// connect_with_retries establishes a connection to an upstream for orig, retrying against
+    /// other endpoints where possible. The load balancer counts the connection against the chosen
+    /// endpoint until the returned guard is dropped.
+    async fn connect_with_retries(
+        &self,
+        remote_addr: IpAddr,
+        orig: SocketAddr,
+        trace_context: Option<&TraceContext>,
+        record: &mut ConnectionRecord,
+    ) -> Result<(Connected, lb::ConnectionGuard), Error> {
+        // Endpoints we failed to connect to; these are avoided when retrying to a VIP.
+        let mut failed: Vec<SocketAddr> = Vec::new();
+        let mut last_err = None;
+        loop {
+            let req = self.build_request(remote_addr, orig, &failed).await?;
+            if let Some(e) = last_err.take() {
+                if failed.contains(&req.destination) {
+                    // Every endpoint of the VIP has already failed, give up.
+                    return Err(e);
+                }
+            }
+            debug!("request from {} to {}", req.source.name, orig);
+            // In-process connections never leave the node, so they are plain TCP whatever the
+            // destination supports.
+            record.protocol = match req.request_type {
+                RequestType::DirectLocal => Protocol::Tcp,
+                _ => req.protocol,
+            };
+            // Unknown sources have no identity, unless the policy lends them ours.
+            record.source_identity =
+                (!req.source.service_account.is_empty()).then(|| req.source.identity());
+            record.source_workload = Some(req.source.clone());
+            record.source_metadata = Some(PeerMetadata::from_workload(
+                &req.source,
+                &self.cfg.cluster_id,
+            ));
+            record.destination = req.destination;
+            record.destination_workload = req.destination_workload.clone();
+            record.destination_identity = req.destination_identity.clone();
+            let lb_guard = self.workloads.track_connection(req.destination);
+            match self.connect(&req, record.source, trace_context).await {
+                Ok(upstream) => {
+                    if let Connected::Hbone(response, _) = &upstream {
+                        // The server describes the destination in its response.
+                        record.destination_metadata =
+                            PeerMetadata::from_headers(response.headers());
+                    }
+                    return Ok((upstream, lb_guard));
+                }
+                Err(e) if self.can_retry(&req, failed.len()) => {
+                    warn!(
+                        "connection to {} failed, retrying another endpoint: {}",
+                        req.destination, e
+                    );
+                    failed.push(req.destination);
+                    last_err = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// can_retry determines if a failed request may be retried against a different endpoint.
+    /// Only requests we load balanced ourselves can be retried; otherwise there is no alternative.
+    fn can_retry(&self, req: &Request, attempts: usize) -> bool {
+        req.from_vip
+            && matches!(
+                req.request_type,
+                RequestType::Direct | RequestType::DirectLocal
+            )
+            && attempts < self.cfg.connect_retries
+    }
+
+    /// connect establishes the connection to the upstream. For HBONE, this includes the CONNECT
+    /// handshake, so a rejection from the remote side can be retried as well. The trace context, if
+    /// any, is propagated to the remote side on the CONNECT request, or to the inbound side of an
+    /// in-process connection.
+    async fn connect(
+        &self,
+        req: &Request,
+        downstream: SocketAddr,
+        trace_context: Option<&TraceContext>,
+    ) -> Result<Connected, Error> {
+        if req.request_type == RequestType::DirectLocal {
+            // The destination is served by our own inbound listener; skip the network round trip
+            // and TLS handshake with ourselves and hand off to the inbound path directly.
+            debug!(
+                "Proxying to {} in-process type {:?}",
+                req.destination, req.request_type
+            );
+            let (outbound, local) = Inbound::connect_local(
+                &self.cfg,
+                &self.workloads,
+                &self.recorder,
+                &req.source,
+                downstream,
+                req.destination,
+                trace_context,
+            )
+            .await?;
+            return Ok(Connected::Local(outbound, local));
+        }
         match req.protocol {
             Protocol::Hbone => {
-                info!(
+                debug!(
                     "Proxying to {} using HBONE via {} type {:#?}",
                     req.destination, req.gateway, req.request_type
                 );
 
-                // Using the raw connection API, instead of client, is a bit annoying, but the only reasonable
-                // way to work around https://github.com/hyperium/hyper/issues/2863
-                // Eventually we will need to implement our own smarter pooling, TLS handshaking, etc anyways.
-                let mut builder = hyper::client::conn::Builder::new();
-                let builder = builder
-                    .http2_only(true)
-                    .http2_initial_stream_window_size(self.cfg.window_size)
-                    .http2_max_frame_size(self.cfg.frame_size)
-                    .http2_initial_connection_window_size(self.cfg.connection_window_size);
-
-                let request = hyper::Request::builder()
+                let mut request = hyper::Request::builder()
                     .uri(&req.destination.to_string())
                     .method(hyper::Method::CONNECT)
                     .version(hyper::Version::HTTP_2)
-                    .header("baggage", baggage(&req))
+                    .header(
+                        baggage::BAGGAGE_HEADER,
+                        PeerMetadata::from_workload(&req.source, &self.cfg.cluster_id).to_string(),
+                    )
+                    // Lets the remote side connect from the client's address in TPROXY mode.
+                    .header(
+                        hyper::header::FORWARDED,
+                        super::forwarded_for(super::to_canonical_ip(downstream)),
+                    )
                     .body(hyper::Body::empty())
                     .unwrap();
+                for (name, value) in trace_context.map(TraceContext::headers).unwrap_or_default() {
+                    request.headers_mut().insert(name, value);
+                }
 
-                let mut request_sender = if self.cfg.tls {
-                    let id = req.source.identity();
-                    let cert = self.cert_manager.fetch_certificate(id).await?;
-                    let connector = cert.connector()?.configure()?;
-                    let tcp_stream = TcpStream::connect(req.gateway).await?;
-                    let tls_stream = connect_tls(connector, tcp_stream).await?;
-                    let (request_sender, connection) = builder
-                        .handshake(tls_stream)
-                        .await
-                        .map_err(Error::HttpHandshake)?;
-                    // spawn a task to poll the connection and drive the HTTP state
-                    tokio::spawn(async move {
-                        if let Err(e) = connection.await {
-                            error!("Error in HBONE connection handshake: {:?}", e);
-                        }
-                    });
-                    request_sender
-                } else {
-                    let tcp_stream = TcpStream::connect(req.gateway).await?;
-                    let (request_sender, connection) = builder
-                        .handshake::<TcpStream, hyper::Body>(tcp_stream)
-                        .await?;
-                    // spawn a task to poll the connection and drive the HTTP state
-                    tokio::spawn(async move {
-                        if let Err(e) = connection.await {
-                            error!("Error in connection: {}", e);
-                        }
-                    });
-                    request_sender
+                // Streams are multiplexed over pooled HTTP/2 connections. The guard holds our stream
+                // reservation on the connection until the tunnel completes.
+                let key = pool::Key {
+                    gateway: req.gateway,
+                    src_id: req.source.identity(),
+                    dst_id: req.destination_identity.clone(),
                 };
+                let (response, stream_guard) = self.pool.send_request(key, request).await?;
+                if !response.status().is_success() {
+                    return Err(Error::HttpStatus(response.status()));
+                }
+                let size = super::header_size(response.headers());
+                if size > self.cfg.http2_max_header_size {
+                    return Err(Error::HeadersTooLarge(size));
+                }
+                Ok(Connected::Hbone(response, stream_guard))
+            }
+            Protocol::Tcp => {
+                debug!(
+                    "Proxying to {} using TCP via {} type {:?}",
+                    req.destination, req.gateway, req.request_type
+                );
+                let outbound =
+                    super::connect_timeout(&self.cfg, req.gateway, Some(req.source.workload_ip))
+                        .await?;
+                Ok(Connected::Tcp(outbound))
+            }
+        }
+    }
 
-                let response = request_sender.send_request(request).await?;
-
+    async fn proxy_to(
+        &self,
+        upstream: Connected,
+        stream: &mut TcpStream,
+        transfer: &Transfer,
+    ) -> Result<(), Error> {
+        match upstream {
+            Connected::Hbone(response, _stream_guard) => {
                 let code = response.status();
                 match hyper::upgrade::on(response).await {
                     Ok(mut upgraded) => {
-                        super::copy_hbone("hbone client", &mut upgraded, &mut stream)
-                            .await
-                            .expect("hbone client copy");
+                        super::copy_hbone("hbone client", stream, &mut upgraded, transfer).await?;
+                    }
+                    Err(e) => {
+                        error!("upgrade error: {}, {}", e, code);
+                        return Err(Error::Http(e));
                     }
-                    Err(e) => error!("upgrade error: {}, {}", e, code),
                 }
-                info!("request complete");
+                debug!("request complete");
                 Ok(())
             }
-            Protocol::Tcp => {
-                info!(
-                    "Proxying to {} using TCP via {} type {:?}",
-                    req.destination, req.gateway, req.request_type
-                );
-                let mut outbound = TcpStream::connect(req.gateway).await?;
-
-                let (mut ri, mut wi) = stream.split();
-                let (mut ro, mut wo) = outbound.split();
-
-                let client_to_server = async {
-                    tokio::io::copy(&mut ri, &mut wo).await?;
-                    wo.shutdown().await
-                };
-
-                let server_to_client = async {
-                    tokio::io::copy(&mut ro, &mut wi).await?;
-                    wi.shutdown().await
-                };
-
-                tokio::try_join!(client_to_server, server_to_client)?;
-
+            Connected::Tcp(mut outbound) => {
+                super::set_keepalive(&self.cfg, &outbound);
+                super::copy_tcp(stream, &mut outbound, self.cfg.splice, transfer).await?;
                 Ok(())
             }
+            Connected::Local(mut outbound, local) => {
+                let copy = super::copy_tcp(stream, &mut outbound, self.cfg.splice, transfer);
+                local.run(transfer, copy).await
+            }
         }
     }
 
-    async fn build_request(&self, downstream: IpAddr, target: SocketAddr) -> Request {
+    async fn build_request(
+        &self,
+        downstream: IpAddr,
+        target: SocketAddr,
+        exclude: &[SocketAddr],
+    ) -> Result<Request, Error> {
         let (source_workload, us, is_vip) = {
-            let source_workload = self
-                .workloads
-                .fetch_workload(&downstream)
-                .await
-                .expect("todo: source must be found");
+            let source_workload = match self.workloads.fetch_workload(&downstream).await {
+                Some(wl) => wl,
+                None => {
+                    let policy = self.cfg.unknown_source_policy;
+                    // Retries exclude the endpoints that failed; only report the first attempt.
+                    // New pods connect before we learn of them, so this is common enough that only
+                    // the metric is reported beyond debug logs.
+                    if exclude.is_empty() {
+                        debug!(
+                            "connection from unknown source {downstream}, applying policy {policy}"
+                        );
+                        UNKNOWN_SOURCE_CONNECTIONS.inc(vec![("policy", policy.to_string())]);
+                    }
+                    match policy {
+                        UnknownSourcePolicy::Reject => {
+                            return Err(Error::UnknownSource(downstream))
+                        }
+                        UnknownSourcePolicy::Passthrough => {
+                            return Ok(Request {
+                                protocol: Protocol::Tcp,
+                                source: self.unknown_source(downstream, policy),
+                                destination: target,
+                                destination_identity: None,
+                                destination_workload: None,
+                                gateway: target,
+                                direction: Direction::Outbound,
+                                request_type: RequestType::Passthrough,
+                                from_vip: false,
+                            });
+                        }
+                        UnknownSourcePolicy::NodeIdentity => {
+                            self.unknown_source(downstream, policy)
+                        }
+                    }
+                }
+            };
 
             // TODO: we want a single lock for source and upstream probably...?
-            let (us, is_vip) = self.workloads.find_upstream(target).await;
+            let (us, is_vip) = self
+                .workloads
+                .find_upstream(target, &source_workload, exclude)
+                .await;
             (source_workload, us, is_vip)
         };
         let mut req = Request {
             protocol: us.workload.protocol,
             source: source_workload.clone(), // TODO drop clone
             destination: SocketAddr::from((us.workload.workload_ip, us.port)),
+            destination_identity: None,
+            destination_workload: None,
             gateway: us
                 .workload
                 .gateway_ip
                 .expect("todo: refactor gateway ip handling"),
             direction: Direction::Outbound, // TODO set this
             request_type: RequestType::Direct,
+            from_vip: is_vip,
         };
-        if source_workload.waypoint_address.is_some() {
+        // The destination may declare ports that need no L7 processing, and so skip the client
+        // waypoint. Unknown destinations declare none.
+        let client_waypoint = source_workload
+            .waypoint_address
+            .filter(|_| !us.workload.client_waypoint_bypass_ports.contains(&us.port));
+        // The server waypoint belongs to the service as a whole, not the endpoint we happened to pick.
+        let server_waypoint = self.workloads.find_waypoint(target);
+        if let Some(waypoint) = client_waypoint {
             // Source has a remote proxy. We should delegate everything to that proxy - do not even resolve VIP.
-            // TODO: add client skipping
             req.request_type = RequestType::ToClientWaypoint;
             // Let the client remote know we are on the outbound path. The remote proxy should strictly
             // validate the identity when we declare this
@@ -229,12 +510,10 @@ impl OutboundConnection {
             // Load balancing decision is deferred to remote proxy
             req.destination = target;
             // Send to the remote proxy
-            req.gateway = SocketAddr::from((source_workload.waypoint_address.unwrap(), 15001));
+            req.gateway = SocketAddr::from((waypoint, 15001));
             // Always use HBONE here
             req.protocol = Protocol::Hbone;
-        } else if us.workload.waypoint_address.is_some() {
-            // TODO: even in this case, we are picking a single upstream pod and deciding if it has a remote proxy.
-            // Typically this is all or nothing, but if not we should probably send to remote proxy if *any* upstream has one.
+        } else if let Some(waypoint) = server_waypoint {
             if is_vip {
                 // Use the original VIP, not translated
                 req.destination = target
@@ -244,37 +523,108 @@ impl OutboundConnection {
             req.protocol = Protocol::Hbone;
             // Let the client remote know we are on the inbound path.
             req.direction = Direction::Inbound;
-            req.gateway = SocketAddr::from((us.workload.waypoint_address.unwrap(), 15006));
+            req.gateway = SocketAddr::from((waypoint, 15006));
+        } else if us.workload.native_hbone {
+            // The workload terminates HBONE itself, so tunnel straight to it rather than to a
+            // ztunnel in front of it; this holds even when it runs on our own node.
+            req.request_type = RequestType::Direct;
+            req.protocol = Protocol::Hbone;
+            req.gateway = SocketAddr::from((us.workload.workload_ip, 15008));
         } else if !us.workload.node.is_empty()
-            && self.cfg.local_node == Some(us.workload.node)
+            && self.cfg.local_node.as_ref() == Some(&us.workload.node)
             && req.protocol == Protocol::Hbone
         {
             // Sending to a node on the same node (ourselves).
-            // In the future this could be optimized to avoid a full network traversal.
+            // This is served in-process by the inbound path, so the gateway is informational only.
             req.request_type = RequestType::DirectLocal;
-            // We would want to send to 127.0.0.1:15008 in theory. However, the inbound listener
-            // expects to lookup the desired certificate based on the destination IP. If we send directly,
-            // we would try to lookup an IP for 127.0.0.1.
-            // Instead, we send to the actual IP, but iptables in the pod ensures traffic is redirected to 15008.
             req.gateway = SocketAddr::from((req.gateway.ip(), 15088));
         } else if us.workload.name.is_empty() {
             req.request_type = RequestType::Passthrough;
         } else {
             req.request_type = RequestType::Direct;
         }
-        req
+        if matches!(
+            req.request_type,
+            RequestType::Direct | RequestType::DirectLocal
+        ) {
+            req.destination_identity = Some(us.workload.identity());
+        }
+        if !us.workload.name.is_empty() {
+            req.destination_workload = Some(us.workload);
+        }
+        Ok(req)
+    }
+
+    /// unknown_source describes a source missing from the workload store. It has no identity, unless
+    /// the policy lends it the identity of this ztunnel.
+    fn unknown_source(&self, ip: IpAddr, policy: UnknownSourcePolicy) -> Workload {
+        let (namespace, service_account) = match policy {
+            UnknownSourcePolicy::NodeIdentity => (
+                self.cfg.proxy_namespace.clone(),
+                self.cfg.proxy_service_account.clone(),
+            ),
+            UnknownSourcePolicy::Reject | UnknownSourcePolicy::Passthrough => Default::default(),
+        };
+        Workload {
+            workload_ip: ip,
+            waypoint_address: None,
+            gateway_ip: None,
+            protocol: Protocol::Tcp,
+            name: String::new(),
+            namespace,
+            service_account,
+            workload_name: String::new(),
+            workload_type: String::new(),
+            canonical_name: String::new(),
+            canonical_revision: String::new(),
+            node: self.cfg.local_node.clone().unwrap_or_default(),
+            locality: Default::default(),
+            status: Default::default(),
+            native_hbone: false,
+            client_waypoint_bypass_ports: vec![],
+        }
     }
 }
 
-fn baggage(r: &Request) -> String {
-    format!("k8s.cluster.name={cluster},k8s.namespace.name={namespace},k8s.{workload_type}.name={workload_name},service.name={name},service.version={version}",
-        cluster="Kubernetes",// todo
-        namespace=r.source.namespace,
-        workload_type=r.source.workload_type,
-        workload_name=r.source.workload_name,
-        name=r.source.canonical_name,
-        version=r.source.canonical_revision,
-    )
+/// UnknownSourcePolicy determines how outbound connections are handled from sources missing from
+/// the workload store, such as pods we have not received over XDS yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownSourcePolicy {
+    /// The connection is closed.
+    Reject,
+    /// The connection is sent to its original destination as plaintext, bypassing waypoints.
+    Passthrough,
+    /// The connection is proxied as usual, authenticated with the identity of this ztunnel.
+    NodeIdentity,
+}
+
+impl Default for UnknownSourcePolicy {
+    fn default() -> Self {
+        UnknownSourcePolicy::Reject
+    }
+}
+
+impl FromStr for UnknownSourcePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(UnknownSourcePolicy::Reject),
+            "passthrough" => Ok(UnknownSourcePolicy::Passthrough),
+            "node_identity" => Ok(UnknownSourcePolicy::NodeIdentity),
+            s => Err(format!("unknown source policy {s}")),
+        }
+    }
+}
+
+impl fmt::Display for UnknownSourcePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UnknownSourcePolicy::Reject => "reject",
+            UnknownSourcePolicy::Passthrough => "passthrough",
+            UnknownSourcePolicy::NodeIdentity => "node_identity",
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -283,8 +633,31 @@ struct Request {
     direction: Direction,
     source: Workload,
     destination: SocketAddr,
+    // The identity we expect the gateway to present, if known.
+    destination_identity: Option<Identity>,
+    // The workload the destination was resolved to, if known.
+    destination_workload: Option<Workload>,
     gateway: SocketAddr,
     request_type: RequestType,
+    // Whether the destination was picked from the endpoints of a VIP.
+    from_vip: bool,
+}
+
+/// Established is an upstream connection made by connect_target, along with its pending record.
+pub(super) struct Established {
+    upstream: Connected,
+    record: ConnectionRecord,
+    span: trace::Span,
+    // Counts the connection against the chosen endpoint until it closes.
+    _lb_guard: lb::ConnectionGuard,
+}
+
+/// Connected is an established connection to an upstream, ready to proxy.
+enum Connected {
+    Hbone(hyper::Response<hyper::Body>, pool::StreamGuard),
+    Tcp(TcpStream),
+    // Served by our own inbound side, which records the connection once proxying is done.
+    Local(TcpStream, LocalConnection),
 }
 
 #[derive(Debug)]
@@ -302,22 +675,9 @@ enum RequestType {
     Passthrough,
 }
 
-async fn connect_tls(
-    mut connector: ConnectConfiguration,
-    stream: TcpStream,
-) -> Result<tokio_boring::SslStream<TcpStream>, tokio_boring::HandshakeError<TcpStream>> {
-    connector.set_verify_hostname(false);
-    connector.set_use_server_name_indication(false);
-    let addr = stream.local_addr();
-    connector.set_verify_callback(boring::ssl::SslVerifyMode::PEER, move |_, x509| {
-        info!("TLS callback for {:?}: {:?}", addr, x509.error());
-        true
-    });
-    tokio_boring::connect(connector, "", stream).await
-}
-
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
 
     use bytes::Bytes;
@@ -325,9 +685,23 @@ mod tests {
     use crate::workload;
     use crate::xds::istio::workload::Protocol as XdsProtocol;
     use crate::xds::istio::workload::Workload as XdsWorkload;
+    use crate::xds::istio::workload::{Port, PortList};
 
     use super::*;
 
+    /// test_outbound creates an outbound connection handler for the workloads in store.
+    fn test_outbound(cfg: Config, store: workload::WorkloadStore) -> OutboundConnection {
+        OutboundConnection {
+            pool: pool::Pool::new(cfg.clone(), identity::SecretManager::new(cfg.clone())),
+            workloads: WorkloadInformation {
+                info: Arc::new(Mutex::new(store)),
+                demand: None,
+            },
+            recorder: Default::default(),
+            cfg,
+        }
+    }
+
     #[tokio::test]
     async fn build_request() {
         let cfg = Config {
@@ -378,17 +752,18 @@ mod tests {
                 virtual_ips: Default::default(),
                 ..Default::default()
             },
+            XdsWorkload {
+                name: "test-native-hbone".to_string(),
+                namespace: "ns".to_string(),
+                address: Bytes::copy_from_slice(&[127, 0, 0, 6]),
+                protocol: XdsProtocol::Direct as i32,
+                node: "local-node".to_string(),
+                native_hbone: true,
+                ..Default::default()
+            },
         ])
         .unwrap();
-        let wi = WorkloadInformation {
-            info: Arc::new(Mutex::new(wl)),
-            demand: None,
-        };
-        let outbound = OutboundConnection {
-            cert_manager: identity::SecretManager::new(cfg.clone()),
-            workloads: wi,
-            cfg,
-        };
+        let outbound = test_outbound(cfg, wl);
 
         compare(
             &outbound,
@@ -454,6 +829,188 @@ mod tests {
             "known dest, local node, HBONE",
         )
         .await;
+
+        compare(
+            &outbound,
+            "127.0.0.6:80",
+            ExpectedRequest {
+                protocol: Protocol::Hbone,
+                destination: "127.0.0.6:80",
+                gateway: "127.0.0.6:15008",
+                request_type: RequestType::Direct,
+            },
+            "known dest, local node, native HBONE",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn build_request_excludes_failed() {
+        let cfg = Config::default();
+        let vip = |ip: [u8; 4]| XdsWorkload {
+            name: "test-vip".to_string(),
+            namespace: "ns".to_string(),
+            address: Bytes::copy_from_slice(&ip),
+            protocol: XdsProtocol::Direct as i32,
+            virtual_ips: HashMap::from([(
+                "127.0.1.1".to_string(),
+                PortList {
+                    ports: vec![Port {
+                        service_port: 80,
+                        target_port: 8080,
+                    }],
+                },
+            )]),
+            ..Default::default()
+        };
+        let wl = workload::WorkloadStore::test_store(vec![
+            XdsWorkload {
+                name: "source-workload".to_string(),
+                namespace: "ns".to_string(),
+                address: Bytes::copy_from_slice(&[127, 0, 0, 1]),
+                ..Default::default()
+            },
+            vip([127, 0, 0, 2]),
+            vip([127, 0, 0, 3]),
+        ])
+        .unwrap();
+        let outbound = test_outbound(cfg, wl);
+
+        let downstream = "127.0.0.1".parse().unwrap();
+        let target = "127.0.1.1:80".parse().unwrap();
+        for _ in 0..4 {
+            let req = outbound
+                .build_request(downstream, target, &["127.0.0.2:8080".parse().unwrap()])
+                .await
+                .unwrap();
+            assert_eq!(req.destination, "127.0.0.3:8080".parse().unwrap());
+            assert!(req.from_vip);
+        }
+    }
+
+    #[tokio::test]
+    async fn build_request_waypoints() {
+        let cfg = Config::default();
+        let vip = |ip: [u8; 4], waypoint: &[u8]| XdsWorkload {
+            name: "test-vip".to_string(),
+            namespace: "ns".to_string(),
+            address: Bytes::copy_from_slice(&ip),
+            protocol: XdsProtocol::Http as i32,
+            waypoint_address: Bytes::copy_from_slice(waypoint),
+            virtual_ips: HashMap::from([(
+                "127.0.1.1".to_string(),
+                PortList {
+                    ports: vec![
+                        Port {
+                            service_port: 80,
+                            target_port: 8080,
+                        },
+                        Port {
+                            service_port: 5432,
+                            target_port: 5432,
+                        },
+                    ],
+                },
+            )]),
+            client_waypoint_bypass_ports: vec![5432],
+            ..Default::default()
+        };
+        let wl = workload::WorkloadStore::test_store(vec![
+            XdsWorkload {
+                name: "source-workload".to_string(),
+                namespace: "ns".to_string(),
+                address: Bytes::copy_from_slice(&[127, 0, 0, 1]),
+                ..Default::default()
+            },
+            XdsWorkload {
+                name: "source-with-waypoint".to_string(),
+                namespace: "ns".to_string(),
+                address: Bytes::copy_from_slice(&[127, 0, 0, 10]),
+                waypoint_address: Bytes::copy_from_slice(&[127, 0, 0, 100]),
+                ..Default::default()
+            },
+            // Only one endpoint reports the service's waypoint.
+            vip([127, 0, 0, 2], &[127, 0, 0, 200]),
+            vip([127, 0, 0, 3], &[]),
+        ])
+        .unwrap();
+        let outbound = test_outbound(cfg, wl);
+
+        let target = "127.0.1.1:80".parse().unwrap();
+        for _ in 0..4 {
+            let req = outbound
+                .build_request("127.0.0.1".parse().unwrap(), target, &[])
+                .await
+                .unwrap();
+            assert_eq!(req.request_type, RequestType::ToServerWaypoint);
+            assert_eq!(req.destination, target);
+            assert_eq!(req.gateway, "127.0.0.200:15006".parse().unwrap());
+        }
+
+        let req = outbound
+            .build_request("127.0.0.10".parse().unwrap(), target, &[])
+            .await
+            .unwrap();
+        assert_eq!(req.request_type, RequestType::ToClientWaypoint);
+        assert_eq!(req.gateway, "127.0.0.100:15001".parse().unwrap());
+
+        // The port the destination bypasses skips the client waypoint, but still reaches the server
+        // waypoint.
+        let req = outbound
+            .build_request(
+                "127.0.0.10".parse().unwrap(),
+                "127.0.1.1:5432".parse().unwrap(),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(req.request_type, RequestType::ToServerWaypoint);
+        assert_eq!(req.gateway, "127.0.0.200:15006".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn build_request_unknown_source() {
+        let outbound = |unknown_source_policy| {
+            let cfg = Config {
+                unknown_source_policy,
+                proxy_service_account: "ztunnel".to_string(),
+                ..Default::default()
+            };
+            let wl = workload::WorkloadStore::test_store(vec![XdsWorkload {
+                name: "test-hbone".to_string(),
+                namespace: "ns".to_string(),
+                address: Bytes::copy_from_slice(&[127, 0, 0, 2]),
+                protocol: XdsProtocol::Http as i32,
+                ..Default::default()
+            }])
+            .unwrap();
+            test_outbound(cfg, wl)
+        };
+        let downstream = "127.0.0.99".parse().unwrap();
+        let target = "127.0.0.2:80".parse().unwrap();
+
+        let res = outbound(UnknownSourcePolicy::Reject)
+            .build_request(downstream, target, &[])
+            .await;
+        assert!(matches!(res, Err(Error::UnknownSource(ip)) if ip == downstream));
+
+        let req = outbound(UnknownSourcePolicy::Passthrough)
+            .build_request(downstream, target, &[])
+            .await
+            .unwrap();
+        assert_eq!(req.request_type, RequestType::Passthrough);
+        assert_eq!(req.protocol, Protocol::Tcp);
+        assert_eq!(req.gateway, target);
+        assert_eq!(req.source.workload_ip, downstream);
+        assert!(req.source.service_account.is_empty());
+
+        let req = outbound(UnknownSourcePolicy::NodeIdentity)
+            .build_request(downstream, target, &[])
+            .await
+            .unwrap();
+        assert_eq!(req.request_type, RequestType::Direct);
+        assert_eq!(req.protocol, Protocol::Hbone);
+        assert_eq!(req.source.service_account, "ztunnel");
     }
 
     #[derive(PartialEq, Debug)]
@@ -471,8 +1028,9 @@ mod tests {
         name: &str,
     ) {
         let req = outbound
-            .build_request("127.0.0.1".parse().unwrap(), to.parse().unwrap())
-            .await;
+            .build_request("127.0.0.1".parse().unwrap(), to.parse().unwrap(), &[])
+            .await
+            .unwrap();
         let req = ExpectedRequest {
             protocol: req.protocol,
             destination: &req.destination.to_string(),
@@ -0,0 +1,576 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use boring::ssl::ConnectConfiguration;
+use drain::Watch;
+use hyper::client::conn::SendRequest;
+use hyper::{Body, Request, Response};
+use tokio::net::TcpStream;
+use tracing::{debug, error, info};
+
+use crate::config::Config;
+use crate::identity;
+use crate::identity::Identity;
+use crate::proxy::Error;
+
+/// Key identifies a set of interchangeable HBONE connections. A stream may only be multiplexed onto
+/// a connection to the same gateway, using the same client identity, and expecting the same peer.
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Key {
+    pub gateway: SocketAddr,
+    pub src_id: Identity,
+    pub dst_id: Option<Identity>,
+}
+
+/// Pool maintains HTTP/2 connections to HBONE gateways and multiplexes CONNECT streams over them.
+/// It is designed to be cheap to clone.
+#[derive(Clone)]
+pub struct Pool {
+    cfg: Config,
+    cert_manager: identity::SecretManager,
+    connections: Arc<Mutex<HashMap<Key, Vec<Connection>>>>,
+    /// connecting holds a lock per key while a new connection is established, so concurrent misses
+    /// for the same key wait for it rather than each opening their own.
+    connecting: Arc<Mutex<HashMap<Key, Arc<tokio::sync::Mutex<()>>>>>,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// PoolStats is a point-in-time snapshot of the state of the pool.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct PoolStats {
+    /// Number of open connections currently held by the pool.
+    pub connections: usize,
+    /// Number of streams in use across all pooled connections.
+    pub active_streams: usize,
+    /// Number of streams that reused an existing connection.
+    pub hits: u64,
+    /// Number of streams that required a new connection.
+    pub misses: u64,
+    /// Number of connections removed from the pool for being idle or closed.
+    pub evictions: u64,
+}
+
+#[derive(Clone)]
+struct Connection {
+    sender: Arc<tokio::sync::Mutex<SendRequest<Body>>>,
+    streams: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+    last_used: Arc<Mutex<Instant>>,
+}
+
+impl Connection {
+    fn is_idle_for(&self, timeout: Duration) -> bool {
+        self.streams.load(Ordering::SeqCst) == 0
+            && self.last_used.lock().unwrap().elapsed() >= timeout
+    }
+}
+
+/// StreamGuard reserves a stream on a pooled connection. The stream is counted against the
+/// connection until the guard is dropped, so callers should hold it for the lifetime of the tunnel.
+pub struct StreamGuard {
+    conn: Connection,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.conn.streams.fetch_sub(1, Ordering::SeqCst);
+        *self.conn.last_used.lock().unwrap() = Instant::now();
+    }
+}
+
+impl Pool {
+    pub fn new(cfg: Config, cert_manager: identity::SecretManager) -> Pool {
+        Pool {
+            cfg,
+            cert_manager,
+            connections: Default::default(),
+            connecting: Default::default(),
+            counters: Default::default(),
+        }
+    }
+
+    /// reap periodically evicts idle and closed connections, so they are closed even if no new
+    /// streams are sent to their key. It runs until drain is signaled.
+    pub async fn reap(self, drain: Watch) {
+        let mut interval = tokio::time::interval(self.cfg.pool_idle_timeout);
+        let reap = async {
+            loop {
+                interval.tick().await;
+                self.evict(&mut self.connections.lock().unwrap());
+            }
+        };
+        tokio::select! {
+            _ = reap => {}
+            _ = drain.signaled() => {}
+        }
+    }
+
+    /// send_request sends the request over a pooled connection for the key, establishing a new
+    /// connection if none is available.
+    pub async fn send_request(
+        &self,
+        key: Key,
+        req: Request<Body>,
+    ) -> Result<(Response<Body>, StreamGuard), Error> {
+        let guard = self.checkout(key).await?;
+        let response = guard.conn.sender.lock().await.send_request(req);
+        match response.await {
+            Ok(response) => Ok((response, guard)),
+            Err(e) => {
+                if e.is_closed() || e.is_canceled() {
+                    guard.conn.closed.store(true, Ordering::SeqCst);
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let connections = self.connections.lock().unwrap();
+        PoolStats {
+            connections: connections.values().map(Vec::len).sum(),
+            active_streams: connections
+                .values()
+                .flatten()
+                .map(|c| c.streams.load(Ordering::SeqCst))
+                .sum(),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn checkout(&self, key: Key) -> Result<StreamGuard, Error> {
+        if let Some(guard) = self.reuse(&key).await {
+            return Ok(guard);
+        }
+
+        let connecting = self
+            .connecting
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let res = {
+            let _connecting = connecting.lock().await;
+            // Whoever held the lock before us may have just established a connection with room for
+            // this stream.
+            match self.reuse(&key).await {
+                Some(guard) => Ok(guard),
+                None => self.connect_new(&key).await,
+            }
+        };
+        let mut pending = self.connecting.lock().unwrap();
+        drop(connecting);
+        // If only the map still refers to the lock, no one else is waiting on it.
+        if pending
+            .get(&key)
+            .map_or(false, |l| Arc::strong_count(l) == 1)
+        {
+            pending.remove(&key);
+        }
+        res
+    }
+
+    /// reuse reserves a stream on a pooled connection for the key that is ready to send requests,
+    /// if there is one.
+    async fn reuse(&self, key: &Key) -> Option<StreamGuard> {
+        while let Some(guard) = self.reserve(key) {
+            // A connection that received a GOAWAY or was reset will fail here; drop it and try the next.
+            let ready = {
+                let mut sender = guard.conn.sender.lock().await;
+                futures::future::poll_fn(|cx| sender.poll_ready(cx)).await
+            };
+            match ready {
+                Ok(()) => {
+                    self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                    debug!("reusing pooled connection to {}", key.gateway);
+                    return Some(guard);
+                }
+                Err(e) => {
+                    debug!("pooled connection to {} is unusable: {}", key.gateway, e);
+                    guard.conn.closed.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+        None
+    }
+
+    /// connect_new establishes a new connection for the key, adds it to the pool, and reserves a
+    /// stream on it.
+    async fn connect_new(&self, key: &Key) -> Result<StreamGuard, Error> {
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let conn = self.connect(key).await?;
+        conn.streams.fetch_add(1, Ordering::SeqCst);
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .push(conn.clone());
+        Ok(StreamGuard { conn })
+    }
+
+    /// reserve finds a connection for the key with spare stream capacity, and reserves a stream on it.
+    fn reserve(&self, key: &Key) -> Option<StreamGuard> {
+        let mut connections = self.connections.lock().unwrap();
+        self.evict(&mut connections);
+        let conn = connections
+            .get(key)?
+            .iter()
+            .find(|c| c.streams.load(Ordering::SeqCst) < self.cfg.pool_max_streams_per_conn)?
+            .clone();
+        conn.streams.fetch_add(1, Ordering::SeqCst);
+        Some(StreamGuard { conn })
+    }
+
+    fn evict(&self, connections: &mut HashMap<Key, Vec<Connection>>) {
+        let idle_timeout = self.cfg.pool_idle_timeout;
+        let mut evicted = 0;
+        connections.retain(|key, conns| {
+            conns.retain(|c| {
+                let keep = !c.closed.load(Ordering::SeqCst) && !c.is_idle_for(idle_timeout);
+                if !keep {
+                    debug!("evicting pooled connection to {}", key.gateway);
+                    evicted += 1;
+                }
+                keep
+            });
+            !conns.is_empty()
+        });
+        self.counters
+            .evictions
+            .fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    async fn connect(&self, key: &Key) -> Result<Connection, Error> {
+        let mut builder = hyper::client::conn::Builder::new();
+        let builder = builder
+            .http2_only(true)
+            .http2_initial_stream_window_size(self.cfg.window_size)
+            .http2_max_frame_size(self.cfg.frame_size)
+            .http2_initial_connection_window_size(self.cfg.connection_window_size)
+            .http2_adaptive_window(self.cfg.http2_adaptive_window)
+            .http2_keep_alive_interval(self.cfg.http2_keepalive_interval)
+            .http2_keep_alive_timeout(self.cfg.http2_keepalive_timeout)
+            // Connections without streams are not pinged: reap closes them after the pool idle
+            // timeout, and pinging them would keep otherwise unused peers busy until then. A dead
+            // peer is still detected on any connection carrying streams.
+            .http2_keep_alive_while_idle(false);
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let driver_closed = closed.clone();
+        let sender = if self.cfg.tls {
+            let cert = self
+                .cert_manager
+                .fetch_certificate(key.src_id.clone())
+                .await?;
+            let connector = cert.connector()?.configure()?;
+            // Connections are shared by many clients, so they are made from our own address.
+            let tcp_stream = super::connect_timeout(&self.cfg, key.gateway, None).await?;
+            super::set_keepalive(&self.cfg, &tcp_stream);
+            let tls_stream = connect_tls(connector, tcp_stream).await?;
+            // The pool hands this connection out for key.dst_id, so the peer must actually be it.
+            verify_peer(key, crate::tls::peer_identity(tls_stream.ssl()))?;
+            let (request_sender, connection) = builder
+                .handshake(tls_stream)
+                .await
+                .map_err(Error::HttpHandshake)?;
+            // spawn a task to poll the connection and drive the HTTP state
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    error!("Error in HBONE connection handshake: {:?}", e);
+                }
+                driver_closed.store(true, Ordering::SeqCst);
+            });
+            request_sender
+        } else {
+            let tcp_stream = super::connect_timeout(&self.cfg, key.gateway, None).await?;
+            super::set_keepalive(&self.cfg, &tcp_stream);
+            let (request_sender, connection) =
+                builder.handshake::<TcpStream, Body>(tcp_stream).await?;
+            // spawn a task to poll the connection and drive the HTTP state
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    error!("Error in connection: {}", e);
+                }
+                driver_closed.store(true, Ordering::SeqCst);
+            });
+            request_sender
+        };
+        debug!("established pooled connection to {}", key.gateway);
+        Ok(Connection {
+            sender: Arc::new(tokio::sync::Mutex::new(sender)),
+            streams: Default::default(),
+            closed,
+            last_used: Arc::new(Mutex::new(Instant::now())),
+        })
+    }
+}
+
+/// verify_peer checks the identity presented by the peer of a connection for key, if an identity is
+/// expected.
+fn verify_peer(key: &Key, peer: Option<Identity>) -> Result<(), Error> {
+    match &key.dst_id {
+        Some(expected) if peer.as_ref() != Some(expected) => Err(Error::PeerIdentityMismatch {
+            expected: expected.clone(),
+            peer,
+        }),
+        _ => Ok(()),
+    }
+}
+
+async fn connect_tls(
+    mut connector: ConnectConfiguration,
+    stream: TcpStream,
+) -> Result<tokio_boring::SslStream<TcpStream>, tokio_boring::HandshakeError<TcpStream>> {
+    connector.set_verify_hostname(false);
+    connector.set_use_server_name_indication(false);
+    let addr = stream.local_addr();
+    connector.set_verify_callback(boring::ssl::SslVerifyMode::PEER, move |_, x509| {
+        info!("TLS callback for {:?}: {:?}", addr, x509.error());
+        true
+    });
+    tokio_boring::connect(connector, "", stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Method, StatusCode};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn connect_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|_req: Request<Body>| async {
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = StatusCode::OK;
+                Ok::<_, hyper::Error>(res)
+            }))
+        });
+        let server = hyper::server::conn::AddrIncoming::from_listener(listener)
+            .map(hyper::Server::builder)
+            .unwrap()
+            .http2_only(true)
+            .serve(service);
+        tokio::spawn(server);
+        addr
+    }
+
+    fn connect_request() -> Request<Body> {
+        Request::builder()
+            .uri("127.0.0.1:80")
+            .method(Method::CONNECT)
+            .version(hyper::Version::HTTP_2)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn test_key(gateway: SocketAddr, sa: &str) -> Key {
+        Key {
+            gateway,
+            src_id: Identity::Spiffe {
+                trust_domain: "cluster.local".to_string(),
+                namespace: "ns".to_string(),
+                service_account: sa.to_string(),
+            },
+            dst_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn multiplex_streams() {
+        let cfg = Config {
+            tls: false,
+            pool_max_streams_per_conn: 2,
+            ..Default::default()
+        };
+        let pool = Pool::new(cfg.clone(), identity::SecretManager::new(cfg));
+        let gateway = connect_server().await;
+
+        let (res, first) = pool
+            .send_request(test_key(gateway, "a"), connect_request())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let (_, second) = pool
+            .send_request(test_key(gateway, "a"), connect_request())
+            .await
+            .unwrap();
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                connections: 1,
+                active_streams: 2,
+                hits: 1,
+                misses: 1,
+                evictions: 0,
+            },
+            "second stream shares the connection"
+        );
+
+        // The connection is at its stream limit, and a different identity must never share it.
+        let (_, third) = pool
+            .send_request(test_key(gateway, "a"), connect_request())
+            .await
+            .unwrap();
+        let (_, fourth) = pool
+            .send_request(test_key(gateway, "b"), connect_request())
+            .await
+            .unwrap();
+        assert_eq!(pool.stats().connections, 3);
+        assert_eq!(pool.stats().misses, 3);
+
+        drop((first, second, third, fourth));
+        assert_eq!(pool.stats().active_streams, 0);
+    }
+
+    #[tokio::test]
+    async fn evict_idle() {
+        let cfg = Config {
+            tls: false,
+            pool_idle_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let pool = Pool::new(cfg.clone(), identity::SecretManager::new(cfg));
+        let gateway = connect_server().await;
+
+        let (_, guard) = pool
+            .send_request(test_key(gateway, "a"), connect_request())
+            .await
+            .unwrap();
+        drop(guard);
+        let (_, _guard) = pool
+            .send_request(test_key(gateway, "a"), connect_request())
+            .await
+            .unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.connections, 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses() {
+        let cfg = Config {
+            tls: false,
+            ..Default::default()
+        };
+        let pool = Pool::new(cfg.clone(), identity::SecretManager::new(cfg));
+        let gateway = connect_server().await;
+
+        let requests = (0..4).map(|_| pool.send_request(test_key(gateway, "a"), connect_request()));
+        let guards: Vec<_> = futures::future::join_all(requests)
+            .await
+            .into_iter()
+            .map(|res| res.unwrap().1)
+            .collect();
+        let stats = pool.stats();
+        assert_eq!(stats.connections, 1, "concurrent misses share one connect");
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.active_streams, 4);
+        drop(guards);
+        assert!(pool.connecting.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reap_idle() {
+        let cfg = Config {
+            tls: false,
+            pool_idle_timeout: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let pool = Pool::new(cfg.clone(), identity::SecretManager::new(cfg));
+        let gateway = connect_server().await;
+        let (signal, drain) = drain::channel();
+        tokio::spawn(pool.clone().reap(drain));
+
+        let (_, guard) = pool
+            .send_request(test_key(gateway, "a"), connect_request())
+            .await
+            .unwrap();
+        drop(guard);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.stats().connections > 0 {
+            assert!(
+                Instant::now() < deadline,
+                "idle connection was never reaped"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(pool.stats().evictions, 1);
+
+        // The reaper stops once drained, rather than holding up shutdown.
+        tokio::time::timeout(Duration::from_secs(5), signal.drain())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keepalive_closes_stalled_connection() {
+        let cfg = Config {
+            tls: false,
+            http2_keepalive_interval: Some(Duration::from_millis(50)),
+            http2_keepalive_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let pool = Pool::new(cfg.clone(), identity::SecretManager::new(cfg));
+        // A peer that accepts the connection but never responds, not even to pings.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stalled, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let conn = pool.connect(&test_key(gateway, "a")).await.unwrap();
+        // Idle connections are not pinged, so open a stream that the peer never answers.
+        let request = conn.sender.lock().await.send_request(connect_request());
+        tokio::spawn(request);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !conn.closed.load(Ordering::SeqCst) {
+            assert!(
+                Instant::now() < deadline,
+                "stalled connection was never closed"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn verify_peer_identity() {
+        let gateway = "127.0.0.1:15008".parse().unwrap();
+        let key = test_key(gateway, "a");
+        assert!(verify_peer(&key, None).is_ok(), "no identity expected");
+
+        let key = Key {
+            dst_id: Some(test_key(gateway, "server").src_id),
+            ..key
+        };
+        assert!(verify_peer(&key, key.dst_id.clone()).is_ok());
+        assert!(matches!(
+            verify_peer(&key, Some(test_key(gateway, "other").src_id)),
+            Err(Error::PeerIdentityMismatch { .. })
+        ));
+        assert!(matches!(
+            verify_peer(&key, None),
+            Err(Error::PeerIdentityMismatch { .. })
+        ));
+    }
+}
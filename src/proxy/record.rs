@@ -0,0 +1,395 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::debug;
+
+use crate::identity::Identity;
+use crate::proxy::baggage::PeerMetadata;
+use crate::proxy::transfer::Transfer;
+use crate::proxy::Error;
+use crate::workload::{Protocol, Workload};
+
+/// ConnectionRecord describes a proxied connection, from when it was accepted until it closed.
+#[derive(Debug, Clone)]
+pub struct ConnectionRecord {
+    pub direction: Direction,
+    pub protocol: Protocol,
+    pub source: SocketAddr,
+    pub source_workload: Option<Workload>,
+    pub source_identity: Option<Identity>,
+    /// Metadata describing the source, as exchanged in HBONE baggage.
+    pub source_metadata: Option<PeerMetadata>,
+    pub destination: SocketAddr,
+    pub destination_workload: Option<Workload>,
+    pub destination_identity: Option<Identity>,
+    /// Metadata describing the destination, as exchanged in HBONE baggage.
+    pub destination_metadata: Option<PeerMetadata>,
+    /// Bytes sent from the source to the destination.
+    pub bytes_sent: u64,
+    /// Bytes received by the source from the destination.
+    pub bytes_received: u64,
+    pub start: SystemTime,
+    pub duration: Duration,
+    pub close_reason: CloseReason,
+    /// The error that closed the connection, if any.
+    pub error: Option<String>,
+    started: Instant,
+    // Bytes already reported to sinks while the connection was open, as (sent, received).
+    reported: (u64, u64),
+}
+
+/// REPORT_INTERVAL is how often the progress of open connections is reported to sinks.
+const REPORT_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// CloseReason describes why a proxied connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Both sides closed the connection.
+    Completed,
+    /// No upstream connection could be established.
+    ConnectFailed,
+    /// No data flowed for the idle timeout.
+    IdleTimeout,
+    /// The connection failed while proxying.
+    Error,
+}
+
+impl CloseReason {
+    /// response_flags returns the Envoy response flags describing the reason, as used by Istio
+    /// access logs and metrics.
+    pub fn response_flags(&self) -> &'static str {
+        match self {
+            CloseReason::Completed => "-",
+            CloseReason::ConnectFailed => "UF",
+            CloseReason::IdleTimeout => "SI",
+            CloseReason::Error => "UC",
+        }
+    }
+}
+
+impl ConnectionRecord {
+    pub(crate) fn new(
+        direction: Direction,
+        protocol: Protocol,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> ConnectionRecord {
+        ConnectionRecord {
+            direction,
+            protocol,
+            source,
+            source_workload: None,
+            source_identity: None,
+            source_metadata: None,
+            destination,
+            destination_workload: None,
+            destination_identity: None,
+            destination_metadata: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            start: SystemTime::now(),
+            duration: Duration::ZERO,
+            close_reason: CloseReason::Completed,
+            error: None,
+            started: Instant::now(),
+            reported: (0, 0),
+        }
+    }
+
+    /// connect_failed completes the record of a connection for which no upstream could be
+    /// established.
+    pub(crate) fn connect_failed(&mut self, err: impl std::fmt::Display) {
+        self.duration = self.started.elapsed();
+        self.close_reason = CloseReason::ConnectFailed;
+        self.error = Some(err.to_string());
+    }
+
+    /// finish completes the record of an established connection once proxying has ended with res.
+    pub(super) fn finish(&mut self, transfer: &Transfer, res: &Result<(), Error>) {
+        self.duration = self.started.elapsed();
+        self.bytes_sent = transfer.sent();
+        self.bytes_received = transfer.received();
+        self.close_reason = match res {
+            Ok(()) => CloseReason::Completed,
+            Err(Error::IdleTimeout(_)) => CloseReason::IdleTimeout,
+            Err(_) => CloseReason::Error,
+        };
+        self.error = res.as_ref().err().map(ToString::to_string);
+    }
+
+    /// unreported_bytes returns the bytes sent and received that were not yet reported to sinks
+    /// while the connection was open.
+    pub(super) fn unreported_bytes(&self) -> (u64, u64) {
+        (
+            self.bytes_sent.saturating_sub(self.reported.0),
+            self.bytes_received.saturating_sub(self.reported.1),
+        )
+    }
+
+    /// source_peer returns what is known about the source. The workload we resolved locally is
+    /// preferred, as the metadata the peer exchanged is only trusted when nothing is known locally.
+    pub fn source_peer(&self) -> Option<PeerMetadata> {
+        peer(
+            &self.source_workload,
+            &self.source_metadata,
+            &self.source_identity,
+        )
+    }
+
+    /// destination_peer returns what is known about the destination, preferring the workload we
+    /// resolved locally over the metadata the peer exchanged.
+    pub fn destination_peer(&self) -> Option<PeerMetadata> {
+        peer(
+            &self.destination_workload,
+            &self.destination_metadata,
+            &self.destination_identity,
+        )
+    }
+}
+
+/// peer describes a peer by its workload, falling back to the metadata it exchanged with us. Peers
+/// can claim anything in their metadata, so it is dropped if it names a namespace other than the
+/// one of the peer's authenticated identity.
+fn peer(
+    workload: &Option<Workload>,
+    metadata: &Option<PeerMetadata>,
+    identity: &Option<Identity>,
+) -> Option<PeerMetadata> {
+    if let Some(w) = workload {
+        // Only the cluster is not known locally; take it from metadata describing the same namespace.
+        let cluster_id = metadata
+            .as_ref()
+            .filter(|m| m.namespace == w.namespace)
+            .map(|m| m.cluster_id.as_str())
+            .unwrap_or_default();
+        return Some(PeerMetadata::from_workload(w, cluster_id));
+    }
+    metadata.clone().filter(|m| match identity {
+        Some(Identity::Spiffe { namespace, .. }) => &m.namespace == namespace,
+        None => true,
+    })
+}
+
+/// RecordSink consumes the record of each proxied connection once it closes, for example to log it
+/// or to update metrics. Sinks are called inline on the connection's task, so must not block.
+pub trait RecordSink: Send + Sync {
+    /// opened is called with the partial record of a connection once its upstream is established.
+    fn opened(&self, _record: &ConnectionRecord) {}
+
+    /// progress is called periodically while a connection is open, with the bytes sent and received
+    /// since its last report.
+    fn progress(&self, _record: &ConnectionRecord, _sent: u64, _received: u64) {}
+
+    fn record(&self, record: &ConnectionRecord);
+}
+
+/// Recorder hands connection records to all registered sinks. It is designed to be cheap to clone.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    sinks: Arc<Vec<Arc<dyn RecordSink>>>,
+}
+
+impl Recorder {
+    pub fn new(sinks: Vec<Arc<dyn RecordSink>>) -> Recorder {
+        Recorder {
+            sinks: Arc::new(sinks),
+        }
+    }
+
+    pub fn opened(&self, record: &ConnectionRecord) {
+        for sink in self.sinks.iter() {
+            sink.opened(record);
+        }
+    }
+
+    /// progress reports the bytes transferred since the last report to the sinks.
+    pub fn progress(&self, record: &mut ConnectionRecord, transfer: &Transfer) {
+        record.bytes_sent = transfer.sent();
+        record.bytes_received = transfer.received();
+        let (sent, received) = record.unreported_bytes();
+        if sent == 0 && received == 0 {
+            return;
+        }
+        record.reported = (record.bytes_sent, record.bytes_received);
+        for sink in self.sinks.iter() {
+            sink.progress(record, sent, received);
+        }
+    }
+
+    /// track drives proxy to completion, reporting its progress every REPORT_INTERVAL so that
+    /// long-lived connections are reflected in metrics before they close.
+    pub async fn track<F>(
+        &self,
+        record: &mut ConnectionRecord,
+        transfer: &Transfer,
+        proxy: F,
+    ) -> Result<(), Error>
+    where
+        F: Future<Output = Result<(), Error>>,
+    {
+        tokio::pin!(proxy);
+        let mut interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + REPORT_INTERVAL,
+            REPORT_INTERVAL,
+        );
+        loop {
+            tokio::select! {
+                res = &mut proxy => return res,
+                _ = interval.tick() => self.progress(record, transfer),
+            }
+        }
+    }
+
+    pub fn record(&self, record: ConnectionRecord) {
+        debug!(
+            direction = ?record.direction,
+            protocol = ?record.protocol,
+            src = %record.source,
+            dst = %record.destination,
+            sent = record.bytes_sent,
+            received = record.bytes_received,
+            duration = ?record.duration,
+            reason = ?record.close_reason,
+            error = record.error.as_deref().unwrap_or_default(),
+            "connection closed"
+        );
+        for sink in self.sinks.iter() {
+            sink.record(&record);
+        }
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("sinks", &self.sinks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records collects connection records, for tests to inspect.
+    #[derive(Default)]
+    pub(crate) struct Records(pub(crate) Mutex<Vec<ConnectionRecord>>);
+
+    impl RecordSink for Records {
+        fn record(&self, record: &ConnectionRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[test]
+    fn close_reason() {
+        let src = "127.0.0.1:1000".parse().unwrap();
+        let dst = "127.0.0.2:80".parse().unwrap();
+        let transfer = Transfer::new(Duration::MAX);
+        let new = || ConnectionRecord::new(Direction::Outbound, Protocol::Tcp, src, dst);
+
+        let mut record = new();
+        record.finish(&transfer, &Ok(()));
+        assert_eq!(record.close_reason, CloseReason::Completed);
+        assert_eq!(record.error, None);
+
+        let mut record = new();
+        record.finish(&transfer, &Err(Error::IdleTimeout(Duration::from_secs(1))));
+        assert_eq!(record.close_reason, CloseReason::IdleTimeout);
+
+        // A dead peer detected by TCP keepalive is an error, not an idle connection.
+        let mut record = new();
+        let dead = std::io::Error::new(std::io::ErrorKind::TimedOut, "keepalive");
+        record.finish(&transfer, &Err(Error::Io(dead)));
+        assert_eq!(record.close_reason, CloseReason::Error);
+
+        let mut record = new();
+        record.finish(
+            &transfer,
+            &Err(Error::HttpStatus(hyper::StatusCode::FORBIDDEN)),
+        );
+        assert_eq!(record.close_reason, CloseReason::Error);
+
+        let mut record = new();
+        record.connect_failed(Error::ConnectTimeout(dst));
+        assert_eq!(record.close_reason, CloseReason::ConnectFailed);
+        assert!(record.error.is_some());
+    }
+
+    #[test]
+    fn peer_metadata_trust() {
+        let mut record = ConnectionRecord::new(
+            Direction::Inbound,
+            Protocol::Hbone,
+            "127.0.0.1:1000".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        );
+        record.source_identity = Some(Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "default".to_string(),
+        });
+        let metadata = |namespace: &str| PeerMetadata {
+            cluster_id: "remote".to_string(),
+            namespace: namespace.to_string(),
+            workload_name: "claimed".to_string(),
+            ..Default::default()
+        };
+
+        record.source_metadata = Some(metadata("ns"));
+        assert_eq!(record.source_peer(), Some(metadata("ns")));
+        record.source_metadata = Some(metadata("other"));
+        assert_eq!(
+            record.source_peer(),
+            None,
+            "namespace disagrees with identity"
+        );
+
+        record.source_workload = Some(Workload {
+            workload_ip: "127.0.0.1".parse().unwrap(),
+            waypoint_address: None,
+            gateway_ip: None,
+            protocol: Protocol::Hbone,
+            name: "local".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "default".to_string(),
+            workload_name: "local".to_string(),
+            workload_type: "deployment".to_string(),
+            canonical_name: "local".to_string(),
+            canonical_revision: "v1".to_string(),
+            node: String::new(),
+            locality: Default::default(),
+            status: Default::default(),
+            native_hbone: false,
+            client_waypoint_bypass_ports: vec![],
+        });
+        record.source_metadata = Some(metadata("ns"));
+        let peer = record.source_peer().unwrap();
+        assert_eq!(peer.workload_name, "local", "local workload preferred");
+        assert_eq!(peer.cluster_id, "remote");
+    }
+
+    #[test]
+    fn recorder_fans_out() {
+        let (a, b) = (Arc::new(Records::default()), Arc::new(Records::default()));
+        let recorder = Recorder::new(vec![a.clone() as Arc<dyn RecordSink>, b.clone()]);
+        recorder.record(ConnectionRecord::new(
+            Direction::Inbound,
+            Protocol::Hbone,
+            "127.0.0.1:1000".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        ));
+        assert_eq!(a.0.lock().unwrap().len(), 1);
+        assert_eq!(b.0.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,309 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use drain::Watch;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+
+use crate::config::Config;
+use crate::proxy::outbound::OutboundConnection;
+use crate::proxy::{pool, ConnectionLimiter, ConnectionTracker, Error};
+use crate::workload::WorkloadInformation;
+
+const VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+// How long a client has to send its SOCKS5 request before it is disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Socks5 is an outbound listener for clients that are not transparently captured. The target of
+/// the SOCKS5 CONNECT request takes the place of the original destination, and is otherwise routed
+/// like any other outbound connection. As it proxies on behalf of the client, only clients on this
+/// node are served.
+pub struct Socks5 {
+    cfg: Config,
+    pool: pool::Pool,
+    workloads: WorkloadInformation,
+    listener: TcpListener,
+    drain: Watch,
+    connections: ConnectionTracker,
+    limiter: ConnectionLimiter,
+}
+
+impl Socks5 {
+    pub(super) async fn new(
+        cfg: Config,
+        addr: SocketAddr,
+        pool: pool::Pool,
+        workloads: WorkloadInformation,
+        drain: Watch,
+        connections: ConnectionTracker,
+        limiter: ConnectionLimiter,
+    ) -> Result<Socks5, Error> {
+        let listener: TcpListener = TcpListener::bind(addr).await.map_err(Error::Bind)?;
+        Ok(Socks5 {
+            cfg,
+            pool,
+            workloads,
+            listener,
+            drain,
+            connections,
+            limiter,
+        })
+    }
+
+    pub(super) async fn run(self) {
+        let addr = self.listener.local_addr().unwrap();
+        info!("socks5 listener established {}", addr);
+
+        let drain = self.drain.clone();
+        let accept = async move {
+            loop {
+                let socket = self.listener.accept().await;
+                match socket {
+                    Ok((stream, remote)) => {
+                        debug!("accepted socks5 connection from {}", remote);
+                        let limit_guard = match self.limiter.acquire(super::to_canonical_ip(remote))
+                        {
+                            Ok(guard) => guard,
+                            Err(e) => {
+                                warn!("rejecting socks5 connection from {}: {}", remote, e);
+                                continue;
+                            }
+                        };
+                        let guard = self.connections.track(self.drain.clone());
+                        let oc = OutboundConnection {
+                            pool: self.pool.clone(),
+                            workloads: self.workloads.clone(),
+                            recorder: self.connections.recorder(),
+                            cfg: self.cfg.clone(),
+                        };
+                        tokio::spawn(async move {
+                            match Self::proxy(oc, stream).await {
+                                Ok(_) => debug!("socks5 proxy complete"),
+                                Err(ref e) => warn!("socks5 proxy failed: {}", e),
+                            };
+                            drop(guard);
+                            drop(limit_guard);
+                        });
+                    }
+                    Err(e) => {
+                        crate::metrics::LISTENER_ERRORS
+                            .inc(vec![("listener", "socks5".to_string())]);
+                        error!("Failed TCP handshake {}", e)
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            res = accept => { res }
+            _ = drain.signaled() => {
+                info!("socks5 drained");
+            }
+        }
+    }
+
+    async fn proxy(oc: OutboundConnection, mut stream: TcpStream) -> Result<(), Error> {
+        let source = stream.peer_addr()?;
+        authorize_peer(&oc, super::to_canonical_ip(source)).await?;
+        let target = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream))
+            .await
+            .map_err(|_| Error::Socks5("timed out waiting for request".into()))??;
+        debug!("socks5 CONNECT to {}", target);
+        // Only report success once the upstream is established, so the client can tell failures apart.
+        match oc.connect_target(source, target).await {
+            Ok(established) => match reply(&mut stream, REPLY_SUCCEEDED).await {
+                Ok(()) => oc.proxy_established(established, &mut stream).await,
+                Err(e) => oc.abandon(established, e),
+            },
+            Err(e) => {
+                if let Err(reply_err) = reply(&mut stream, reply_code(&e)).await {
+                    debug!("failed to send socks5 reply: {}", reply_err);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// authorize_peer admits SOCKS5 clients on this node: loopback, or workloads scheduled here.
+/// Anyone else could otherwise have us originate connections with the identity of our workloads.
+async fn authorize_peer(oc: &OutboundConnection, peer: IpAddr) -> Result<(), Error> {
+    if peer.is_loopback() {
+        return Ok(());
+    }
+    match oc.workloads.fetch_workload(&peer).await {
+        Some(wl) if !wl.node.is_empty() && oc.cfg.local_node.as_ref() == Some(&wl.node) => Ok(()),
+        _ => Err(Error::Socks5(format!("client {peer} is not on this node"))),
+    }
+}
+
+/// reply_code maps a failure to connect to the target to its SOCKS5 reply (RFC 1928, section 6).
+fn reply_code(err: &Error) -> u8 {
+    match err {
+        Error::Io(e) if e.kind() == io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        Error::Io(_) | Error::ConnectTimeout(_) => REPLY_HOST_UNREACHABLE,
+        Error::HttpStatus(status) if *status == hyper::StatusCode::FORBIDDEN => REPLY_NOT_ALLOWED,
+        Error::HttpStatus(_) => REPLY_HOST_UNREACHABLE,
+        Error::LocalRejected(_) | Error::UnknownSource(_) | Error::PeerIdentityMismatch { .. } => {
+            REPLY_NOT_ALLOWED
+        }
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+/// handshake negotiates a SOCKS5 CONNECT request (RFC 1928) without authentication, returning
+/// the requested target. The reply to a valid request is left to the caller, once it knows whether
+/// the target can be reached.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<SocketAddr, Error> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let [version, nmethods] = header;
+    if version != VERSION {
+        return Err(Error::Socks5(format!("unsupported version {version}")));
+    }
+    let mut methods = vec![0u8; nmethods as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(Error::Socks5("no supported authentication method".into()));
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, cmd, _, atyp] = request;
+    if version != VERSION {
+        return Err(Error::Socks5(format!("unsupported version {version}")));
+    }
+    if cmd != CMD_CONNECT {
+        reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(Error::Socks5(format!("unsupported command {cmd}")));
+    }
+    let target = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            SocketAddr::new(Ipv4Addr::from(octets).into(), stream.read_u16().await?)
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            SocketAddr::new(Ipv6Addr::from(octets).into(), stream.read_u16().await?)
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0u8; len as usize];
+            stream.read_exact(&mut domain).await?;
+            let domain = String::from_utf8_lossy(&domain).into_owned();
+            let port = stream.read_u16().await?;
+            let resolved = tokio::net::lookup_host((domain.as_str(), port))
+                .await
+                .ok()
+                .and_then(|mut addrs| addrs.next());
+            match resolved {
+                Some(addr) => addr,
+                None => {
+                    reply(stream, REPLY_HOST_UNREACHABLE).await?;
+                    return Err(Error::Socks5(format!("failed to resolve {domain}")));
+                }
+            }
+        }
+        _ => {
+            reply(stream, REPLY_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            return Err(Error::Socks5(format!("unsupported address type {atyp}")));
+        }
+    };
+    Ok(target)
+}
+
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, code: u8) -> Result<(), Error> {
+    // We do not expose the bound address; clients ignore it for CONNECT.
+    stream
+        .write_all(&[VERSION, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn negotiate(request: &[u8]) -> (Result<SocketAddr, Error>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+        let res = handshake(&mut server).await;
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        (res, response)
+    }
+
+    #[tokio::test]
+    async fn connect_ipv4() {
+        let (res, response) = negotiate(&[
+            5, 1, 0, // greeting, no auth
+            5, 1, 0, 1, 127, 0, 0, 1, 0x1f, 0x90, // CONNECT 127.0.0.1:8080
+        ])
+        .await;
+        assert_eq!(res.unwrap(), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(response, [5, 0], "no reply until connected");
+    }
+
+    #[tokio::test]
+    async fn connect_domain() {
+        let mut request = vec![5, 1, 0, 5, 1, 0, 3, 9];
+        request.extend_from_slice(b"localhost");
+        request.extend_from_slice(&[0, 80]);
+        let (res, _) = negotiate(&request).await;
+        assert_eq!(res.unwrap().port(), 80);
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported() {
+        let (res, response) = negotiate(&[5, 1, 2]).await;
+        assert!(res.is_err(), "username/password auth only");
+        assert_eq!(response, [5, METHOD_NONE_ACCEPTABLE]);
+
+        let (res, response) = negotiate(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80]).await;
+        assert!(res.is_err(), "BIND");
+        assert_eq!(
+            response,
+            [5, 0, 5, REPLY_COMMAND_NOT_SUPPORTED, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn reply_codes() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(reply_code(&refused.into()), REPLY_CONNECTION_REFUSED);
+        let addr = "127.0.0.1:80".parse().unwrap();
+        assert_eq!(
+            reply_code(&Error::ConnectTimeout(addr)),
+            REPLY_HOST_UNREACHABLE
+        );
+        assert_eq!(
+            reply_code(&Error::HttpStatus(hyper::StatusCode::FORBIDDEN)),
+            REPLY_NOT_ALLOWED
+        );
+        assert_eq!(
+            reply_code(&Error::LocalRejected("other node".into())),
+            REPLY_NOT_ALLOWED
+        );
+    }
+}
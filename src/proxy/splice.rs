@@ -0,0 +1,117 @@
+//! Zero-copy proxying between TCP sockets using splice(2).
+//!
+//! Data is moved from the source socket into a pipe, and from the pipe into the destination
+//! socket, without ever being copied into userspace.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+use super::transfer::{Flow, Transfer};
+
+// The amount of data we attempt to move per splice call. This matches the pipe capacity we request.
+const PIPE_SIZE: usize = 1024 * 1024;
+
+/// Pipe is a non-blocking pipe used as the intermediate buffer for a splice.
+pub(super) struct Pipe {
+    r: RawFd,
+    w: RawFd,
+}
+
+impl Pipe {
+    #[allow(unsafe_code)]
+    pub(super) fn new() -> io::Result<Pipe> {
+        let mut fds = [0 as libc::c_int; 2];
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pipe = Pipe {
+            r: fds[0],
+            w: fds[1],
+        };
+        // A larger pipe means fewer syscalls; failing to grow it is not fatal.
+        unsafe { libc::fcntl(pipe.w, libc::F_SETPIPE_SZ, PIPE_SIZE as libc::c_int) };
+        Ok(pipe)
+    }
+}
+
+impl Drop for Pipe {
+    #[allow(unsafe_code)]
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.r);
+            libc::close(self.w);
+        }
+    }
+}
+
+#[allow(unsafe_code)]
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+#[allow(unsafe_code)]
+fn shutdown_write(stream: &TcpStream) -> io::Result<()> {
+    let ret = unsafe { libc::shutdown(stream.as_raw_fd(), libc::SHUT_WR) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// copy moves all data from `from` to `to` through the pipe until EOF, then shuts down the write
+/// side of `to`. Copied bytes are accounted for in flow of transfer; the total is returned.
+pub(super) async fn copy(
+    from: &TcpStream,
+    to: &TcpStream,
+    pipe: Pipe,
+    transfer: &Transfer,
+    flow: Flow,
+) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        from.readable().await?;
+        let n = match from.try_io(Interest::READABLE, || {
+            splice(from.as_raw_fd(), pipe.w, PIPE_SIZE)
+        }) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            shutdown_write(to)?;
+            return Ok(total);
+        }
+
+        // Drain everything we just read before reading more, so the pipe never fills up.
+        let mut remaining = n;
+        while remaining > 0 {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || {
+                splice(pipe.r, to.as_raw_fd(), remaining)
+            }) {
+                Ok(m) => remaining -= m,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total += n as u64;
+        transfer.add(flow, n as u64);
+    }
+}
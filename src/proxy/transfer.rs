@@ -0,0 +1,162 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Instant;
+
+use crate::proxy::Error;
+
+/// Transfer accounts for the data flowing over a proxied connection: the bytes sent in each
+/// direction, and when data last flowed, so idle connections can be closed.
+/// It is designed to be cheap to clone.
+#[derive(Clone)]
+pub struct Transfer {
+    idle_timeout: Duration,
+    start: Instant,
+    // Milliseconds since start of the last activity.
+    last: Arc<AtomicU64>,
+    sent: Arc<AtomicU64>,
+    received: Arc<AtomicU64>,
+}
+
+/// Flow is the direction data moves over a proxied connection.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Flow {
+    /// From the downstream client to the upstream.
+    Sent,
+    /// From the upstream back to the downstream client.
+    Received,
+}
+
+impl Transfer {
+    /// new creates a Transfer for a connection that is closed once no data flows for idle_timeout.
+    pub fn new(idle_timeout: Duration) -> Transfer {
+        Transfer {
+            idle_timeout,
+            start: Instant::now(),
+            last: Default::default(),
+            sent: Default::default(),
+            received: Default::default(),
+        }
+    }
+
+    /// sent returns the bytes read from the downstream client so far.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// received returns the bytes read from the upstream so far.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// add accounts for n bytes moved in flow, marking the connection as active now.
+    pub(super) fn add(&self, flow: Flow, n: u64) {
+        let counter = match flow {
+            Flow::Sent => &self.sent,
+            Flow::Received => &self.received,
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// reader wraps reader so that any data read from it is accounted for in flow.
+    pub(super) fn reader<R>(&self, reader: R, flow: Flow) -> TransferReader<R> {
+        TransferReader {
+            inner: reader,
+            transfer: self.clone(),
+            flow,
+        }
+    }
+
+    /// idle completes once there has been no activity for the idle timeout.
+    async fn idle(&self) {
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = match last.checked_add(self.idle_timeout) {
+                Some(deadline) => deadline,
+                // Effectively no timeout.
+                None => return futures::future::pending().await,
+            };
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+
+    /// run runs copy, failing with Error::IdleTimeout if it is idle for the idle timeout. That is
+    /// kept apart from any I/O error copy fails with, such as a TCP keepalive timing out.
+    pub(super) async fn run<F>(&self, copy: F) -> Result<(), Error>
+    where
+        F: Future<Output = io::Result<()>>,
+    {
+        tokio::select! {
+            res = copy => res.map_err(Error::Io),
+            _ = self.idle() => Err(Error::IdleTimeout(self.idle_timeout)),
+        }
+    }
+}
+
+pub(super) struct TransferReader<R> {
+    inner: R,
+    transfer: Transfer,
+    flow: Flow,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TransferReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.transfer.add(self.flow, n as u64);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let transfer = Transfer::new(Duration::from_millis(100));
+        let copy = {
+            let transfer = transfer.clone();
+            async move {
+                for _ in 0..3 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    transfer.add(Flow::Sent, 1);
+                }
+                Ok(())
+            }
+        };
+        transfer
+            .run(copy)
+            .await
+            .expect("active connection must not time out");
+        assert_eq!(transfer.sent(), 3);
+        assert_eq!(transfer.received(), 0);
+
+        let copy = futures::future::pending();
+        let err = transfer.run(copy).await.unwrap_err();
+        assert!(matches!(err, Error::IdleTimeout(_)), "{err}");
+
+        // Timeouts of the copy itself are not idle timeouts.
+        let copy = async { Err(io::Error::new(io::ErrorKind::TimedOut, "keepalive")) };
+        let err = transfer.run(copy).await.unwrap_err();
+        assert!(matches!(err, Error::Io(_)), "{err}");
+    }
+}
@@ -1,14 +1,52 @@
-use std::net::SocketAddr;
-use std::os::unix::io::AsRawFd;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str::FromStr;
+use std::time::Duration;
 
 use tokio::io;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tracing::warn;
+
+/// CaptureMode is how traffic is captured into our listeners, which determines how the original
+/// destination of a connection is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// NAT REDIRECT rules rewrite the destination, which is recovered with SO_ORIGINAL_DST.
+    Redirect,
+    /// TPROXY rules deliver connections unmodified, so the destination is the local address of the
+    /// socket. Upstream connections are made from the client's address in turn.
+    Tproxy,
+    /// Connections may be captured either way: SO_ORIGINAL_DST is used where the connection was
+    /// redirected, and the local address otherwise.
+    Auto,
+}
+
+impl Default for CaptureMode {
+    fn default() -> Self {
+        CaptureMode::Redirect
+    }
+}
+
+impl FromStr for CaptureMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redirect" => Ok(CaptureMode::Redirect),
+            "tproxy" => Ok(CaptureMode::Tproxy),
+            "auto" => Ok(CaptureMode::Auto),
+            s => Err(format!("unknown capture mode {s}")),
+        }
+    }
+}
 
-#[cfg(target_os = "linux")]
-#[allow(unsafe_code)]
 pub fn set_transparent(l: &TcpListener) -> io::Result<()> {
-    let fd = l.as_raw_fd();
+    set_transparent_fd(l.as_raw_fd())
+}
 
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+fn set_transparent_fd(fd: RawFd) -> io::Result<()> {
     unsafe {
         let optval: libc::c_int = 1;
         let ret = libc::setsockopt(
@@ -25,16 +63,82 @@ pub fn set_transparent(l: &TcpListener) -> io::Result<()> {
     Ok(())
 }
 
-#[cfg(target_os = "linux")]
-#[allow(unsafe_code)]
-pub fn orig_dst_addr_fd<T: AsRawFd>(sock: T) -> io::Result<SocketAddr> {
+/// orig_dst_addr_fd returns the address a captured connection was originally destined to.
+pub fn orig_dst_addr_fd<T: AsRawFd>(sock: T, mode: CaptureMode) -> io::Result<SocketAddr> {
     let fd = sock.as_raw_fd();
+    match mode {
+        CaptureMode::Redirect => so_original_dst(fd).map_err(|e| {
+            warn!("failed to read SO_ORIGINAL_DST: {:?}", e);
+            e
+        }),
+        CaptureMode::Tproxy => local_addr(fd),
+        CaptureMode::Auto => so_original_dst(fd).or_else(|_| local_addr(fd)),
+    }
+}
 
+pub fn orig_dst_addr(sock: &TcpStream, mode: CaptureMode) -> io::Result<SocketAddr> {
+    orig_dst_addr_fd(sock.as_raw_fd(), mode)
+}
+
+/// Dialer creates every upstream connection, so the options that set our own traffic apart apply
+/// to all of them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dialer {
+    /// The SO_MARK set on sockets, letting redirection rules exempt our own traffic.
+    pub mark: Option<u32>,
+    /// If true, connections are made from the address of the client they carry traffic for, which
+    /// need not be local. This needs CAP_NET_ADMIN, and routing that delivers replies back to us, as
+    /// set up for TPROXY.
+    pub transparent: bool,
+}
+
+impl Dialer {
+    /// connect connects to dst, on behalf of a client at source if the connection carries its
+    /// traffic.
+    pub async fn connect(&self, dst: SocketAddr, source: Option<IpAddr>) -> io::Result<TcpStream> {
+        let socket = match dst {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(mark) = self.mark {
+            set_mark_fd(socket.as_raw_fd(), mark)?;
+        }
+        let source = match (dst, source.filter(|_| self.transparent)) {
+            (SocketAddr::V6(_), Some(IpAddr::V4(ip))) => Some(ip.to_ipv6_mapped().into()),
+            // An IPv6 client cannot be impersonated towards an IPv4 destination.
+            (SocketAddr::V4(_), Some(IpAddr::V6(_))) => None,
+            (_, source) => source,
+        };
+        if let Some(source) = source {
+            set_transparent_fd(socket.as_raw_fd())?;
+            socket.bind(SocketAddr::new(source, 0))?;
+        }
+        socket.connect(dst).await
+    }
+
+    /// connect_udp creates a UDP socket connected to dst, for our own traffic.
+    pub async fn connect_udp(&self, dst: SocketAddr) -> io::Result<UdpSocket> {
+        let bind = match dst {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        if let Some(mark) = self.mark {
+            set_mark_fd(socket.as_raw_fd(), mark)?;
+        }
+        socket.connect(dst).await?;
+        Ok(socket)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+fn so_original_dst(fd: RawFd) -> io::Result<SocketAddr> {
     unsafe { linux::so_original_dst(fd) }
 }
 
 #[cfg(not(target_os = "linux"))]
-pub fn orig_dst_addr_fd<T: AsRawFd>(_: T) -> io::Result<SocketAddr> {
+fn so_original_dst(_: RawFd) -> io::Result<SocketAddr> {
     Err(io::Error::new(
         io::ErrorKind::Other,
         "SO_ORIGINAL_DST not supported on this operating system",
@@ -43,29 +147,88 @@ pub fn orig_dst_addr_fd<T: AsRawFd>(_: T) -> io::Result<SocketAddr> {
 
 #[cfg(target_os = "linux")]
 #[allow(unsafe_code)]
-pub fn orig_dst_addr(sock: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
-    let fd = sock.as_raw_fd();
+fn local_addr(fd: RawFd) -> io::Result<SocketAddr> {
+    unsafe { linux::local_addr(fd) }
+}
 
-    unsafe { linux::so_original_dst(fd) }
+#[cfg(not(target_os = "linux"))]
+fn local_addr(_: RawFd) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "reading the local address of a socket is not supported on this operating system",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+fn set_mark_fd(fd: RawFd, mark: u32) -> io::Result<()> {
+    unsafe { linux::setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int) }
 }
 
 #[cfg(not(target_os = "linux"))]
-pub fn orig_dst_addr(_: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
+fn set_mark_fd(_: RawFd, _: u32) -> io::Result<()> {
     Err(io::Error::new(
         io::ErrorKind::Other,
-        "SO_ORIGINAL_DST not supported on this operating system",
+        "SO_MARK not supported on this operating system",
     ))
 }
 
 #[cfg(not(target_os = "linux"))]
-#[allow(unsafe_code)]
-pub fn set_transparent(_: &TcpListener) -> io::Result<()> {
+fn set_transparent_fd(_: RawFd) -> io::Result<()> {
     Err(io::Error::new(
         io::ErrorKind::Other,
         "IP_TRANSPARENT not supported on this operating system",
     ))
 }
 
+/// Keepalive configures TCP keepalive probes on a socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long a connection must be idle before probes are sent.
+    pub time: Duration,
+    /// The interval between probes.
+    pub interval: Duration,
+    /// How many unanswered probes before the connection is considered dead.
+    pub retries: u32,
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+pub fn set_keepalive<T: AsRawFd>(sock: &T, keepalive: &Keepalive) -> io::Result<()> {
+    let fd = sock.as_raw_fd();
+
+    unsafe {
+        linux::setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        linux::setsockopt_int(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPIDLE,
+            keepalive.time.as_secs() as libc::c_int,
+        )?;
+        linux::setsockopt_int(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPINTVL,
+            keepalive.interval.as_secs() as libc::c_int,
+        )?;
+        linux::setsockopt_int(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPCNT,
+            keepalive.retries as libc::c_int,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_keepalive<T: AsRawFd>(_: &T, _: &Keepalive) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "TCP keepalive tuning not supported on this operating system",
+    ))
+}
+
 #[cfg(target_os = "linux")]
 #[allow(unsafe_code)]
 mod linux {
@@ -73,28 +236,71 @@ mod linux {
     use std::os::unix::io::RawFd;
     use std::{io, mem};
 
-    use log::warn;
+    pub unsafe fn setsockopt_int(
+        fd: RawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let ret = libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
 
     pub unsafe fn so_original_dst(fd: RawFd) -> io::Result<SocketAddr> {
+        let (level, name) = if is_ipv6(&local_addr(fd)?) {
+            (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+        } else {
+            (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+        };
         let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
         let mut socklen: libc::socklen_t = mem::size_of::<libc::sockaddr_storage>() as u32;
 
         let ret = libc::getsockopt(
             fd,
-            libc::SOL_IP,
-            libc::SO_ORIGINAL_DST,
+            level,
+            name,
             &mut sockaddr as *mut _ as *mut _,
             &mut socklen as *mut _ as *mut _,
         );
         if ret != 0 {
-            let e = io::Error::last_os_error();
-            warn!("failed to read SO_ORIGINAL_DST: {:?}", e);
-            return Err(e);
+            return Err(io::Error::last_os_error());
         }
 
         mk_addr(&sockaddr, socklen)
     }
 
+    pub unsafe fn local_addr(fd: RawFd) -> io::Result<SocketAddr> {
+        let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
+        let mut socklen: libc::socklen_t = mem::size_of::<libc::sockaddr_storage>() as u32;
+
+        let ret = libc::getsockname(
+            fd,
+            &mut sockaddr as *mut _ as *mut _,
+            &mut socklen as *mut _ as *mut _,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        mk_addr(&sockaddr, socklen)
+    }
+
+    /// is_ipv6 determines if a connection with local address addr is IPv6, and so redirected by
+    /// ip6tables. IPv4 connections accepted on dual-stack sockets have IPv4-mapped addresses, and
+    /// are redirected by iptables.
+    pub(super) fn is_ipv6(addr: &SocketAddr) -> bool {
+        matches!(addr, SocketAddr::V6(a) if a.ip().to_ipv4_mapped().is_none())
+    }
+
     // Borrowed with love from net2-rs
     // https://github.com/rust-lang-nursery/net2-rs/blob/1b4cb4fb05fbad750b271f38221eab583b666e5e/src/socket.rs#L103
     //
@@ -164,3 +370,61 @@ mod linux {
         <u32>::from_be(i)
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::net::{Ipv6Addr, SocketAddr};
+
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+    use super::linux::is_ipv6;
+    use super::{orig_dst_addr, CaptureMode, Dialer};
+
+    #[tokio::test]
+    async fn dual_stack_address_family() {
+        let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for (connect, ipv6) in [("127.0.0.1", false), ("::1", true)] {
+            let addr = SocketAddr::new(connect.parse().unwrap(), port);
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            assert_eq!(is_ipv6(&conn.local_addr().unwrap()), ipv6, "{connect}");
+        }
+    }
+
+    #[tokio::test]
+    async fn capture_mode_destination() {
+        assert_eq!("tproxy".parse(), Ok(CaptureMode::Tproxy));
+        assert!("nat".parse::<CaptureMode>().is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        // The connection was not redirected, so only modes that accept the local address find it.
+        assert!(orig_dst_addr(&conn, CaptureMode::Redirect).is_err());
+        assert_eq!(orig_dst_addr(&conn, CaptureMode::Tproxy).unwrap(), addr);
+        assert_eq!(orig_dst_addr(&conn, CaptureMode::Auto).unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn dialer_without_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Without transparent mode, the client's address is never bound.
+        let source = Some("10.0.0.1".parse().unwrap());
+        let conn = Dialer::default().connect(addr, source).await.unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), addr);
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, conn.local_addr().unwrap());
+
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let socket = Dialer::default().connect_udp(addr).await.unwrap();
+        socket.send(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        let (n, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(from, socket.local_addr().unwrap());
+    }
+}
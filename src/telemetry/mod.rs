@@ -1,3 +1,5 @@
+pub mod trace;
+
 use tracing_subscriber::prelude::*;
 
 #[cfg(feature = "console")]
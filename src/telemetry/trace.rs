@@ -0,0 +1,533 @@
+//! Distributed tracing of proxied connections.
+//!
+//! A span is created for each proxied connection. The W3C trace context
+//! (https://www.w3.org/TR/trace-context/) is propagated on HBONE CONNECT requests, so the inbound
+//! side continues the trace of the outbound side. Sampled spans are exported to a collector with
+//! OTLP over HTTP, using the JSON encoding.
+
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::http::HeaderValue;
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::proxy::{CloseReason, ConnectionRecord};
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+// Spans buffered for export before new ones are dropped.
+const BUFFERED_SPANS: usize = 2048;
+// The most spans sent to the collector in a single request.
+const MAX_BATCH: usize = 512;
+
+static TRACER: OnceCell<Tracer> = OnceCell::new();
+
+/// init installs a tracer exporting to endpoint, which spans are started from. Until then, spans
+/// are not recorded. Must be called from within a tokio runtime.
+pub fn init(endpoint: hyper::Uri, sampling: f64, timeout: Duration) {
+    if TRACER
+        .set(Tracer::new(endpoint, sampling, timeout))
+        .is_err()
+    {
+        debug!("tracer already initialized");
+    }
+}
+
+/// start begins a span with the global tracer, continuing the trace of parent if set.
+pub fn start(name: &'static str, kind: SpanKind, parent: Option<TraceContext>) -> Span {
+    match TRACER.get() {
+        Some(tracer) => tracer.start(name, kind, parent),
+        None => Span::disabled(),
+    }
+}
+
+/// TraceContext identifies a span within a trace, as carried by the traceparent and tracestate
+/// headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    /// Vendor specific trace state, passed along unmodified.
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// from_headers extracts the trace context of a request, if present and valid.
+    pub fn from_headers(headers: &hyper::HeaderMap) -> Option<TraceContext> {
+        let mut ctx = Self::parse_traceparent(headers.get(TRACEPARENT_HEADER)?.to_str().ok()?)?;
+        ctx.state = headers
+            .get(TRACESTATE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string);
+        Some(ctx)
+    }
+
+    fn parse_traceparent(value: &str) -> Option<TraceContext> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        // Future versions may append fields, but version 00 has exactly four.
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        let ctx = TraceContext {
+            trace_id: decode_hex(trace_id)?,
+            span_id: decode_hex(span_id)?,
+            sampled: decode_hex::<1>(flags)?[0] & 0x01 != 0,
+            state: None,
+        };
+        // All zero IDs are invalid.
+        if ctx.trace_id == [0; 16] || ctx.span_id == [0; 8] {
+            return None;
+        }
+        Some(ctx)
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            u8::from(self.sampled)
+        )
+    }
+
+    /// headers returns the headers propagating this context to a request.
+    pub fn headers(&self) -> Vec<(&'static str, HeaderValue)> {
+        let mut headers = vec![(
+            TRACEPARENT_HEADER,
+            HeaderValue::from_str(&self.traceparent()).expect("traceparent is valid"),
+        )];
+        if let Some(state) = self
+            .state
+            .as_deref()
+            .and_then(|s| HeaderValue::from_str(s).ok())
+        {
+            headers.push((TRACESTATE_HEADER, state));
+        }
+        headers
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanKind {
+    Server,
+    Client,
+}
+
+/// Tracer starts spans and exports those sampled to a collector. It is designed to be cheap to
+/// clone.
+#[derive(Clone)]
+pub struct Tracer {
+    sampling: f64,
+    spans: mpsc::Sender<SpanData>,
+}
+
+impl Tracer {
+    /// new creates a tracer exporting to endpoint, sampling the given ratio of new traces. Traces
+    /// continued from a parent follow the parent's sampling decision. Export requests are abandoned
+    /// after timeout, so an unresponsive collector cannot hold up later spans.
+    pub fn new(endpoint: hyper::Uri, sampling: f64, timeout: Duration) -> Tracer {
+        let (spans, rx) = mpsc::channel(BUFFERED_SPANS);
+        tokio::spawn(export(endpoint, timeout, rx));
+        Tracer { sampling, spans }
+    }
+
+    pub fn start(&self, name: &'static str, kind: SpanKind, parent: Option<TraceContext>) -> Span {
+        let mut rng = rand::thread_rng();
+        let span_id = loop {
+            let id: [u8; 8] = rng.gen();
+            if id != [0; 8] {
+                break id;
+            }
+        };
+        let (ctx, parent_span_id) = match parent {
+            Some(parent) => (
+                TraceContext {
+                    span_id,
+                    ..parent.clone()
+                },
+                Some(parent.span_id),
+            ),
+            None => (
+                TraceContext {
+                    trace_id: loop {
+                        let id: [u8; 16] = rng.gen();
+                        if id != [0; 16] {
+                            break id;
+                        }
+                    },
+                    span_id,
+                    sampled: rng.gen::<f64>() < self.sampling,
+                    state: None,
+                },
+                None,
+            ),
+        };
+        Span {
+            data: Some(SpanData {
+                ctx,
+                parent_span_id,
+                name,
+                kind,
+                start: SystemTime::now(),
+                end: SystemTime::now(),
+                attributes: Vec::new(),
+                error: None,
+            }),
+            tracer: Some(self.clone()),
+        }
+    }
+}
+
+/// Span is an in-progress span, exported when ended if sampled.
+pub struct Span {
+    data: Option<SpanData>,
+    tracer: Option<Tracer>,
+}
+
+#[derive(Debug)]
+struct SpanData {
+    ctx: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    // Set if the span failed, with a description of the failure.
+    error: Option<String>,
+}
+
+impl Span {
+    /// disabled returns a span that records nothing, used when tracing is not enabled.
+    pub fn disabled() -> Span {
+        Span {
+            data: None,
+            tracer: None,
+        }
+    }
+
+    /// context returns the context to propagate to continue the trace, unless tracing is disabled.
+    pub fn context(&self) -> Option<TraceContext> {
+        self.data.as_ref().map(|d| d.ctx.clone())
+    }
+
+    /// end completes the span with the outcome of the connection in record.
+    pub fn end(mut self, record: &ConnectionRecord) {
+        let (Some(mut data), Some(tracer)) = (self.data.take(), self.tracer.take()) else {
+            return;
+        };
+        if !data.ctx.sampled {
+            return;
+        }
+        data.end = SystemTime::now();
+        data.attributes = record_attributes(record);
+        if record.close_reason != CloseReason::Completed {
+            data.error = Some(
+                record
+                    .error
+                    .clone()
+                    .unwrap_or_else(|| format!("{:?}", record.close_reason)),
+            );
+        }
+        if tracer.spans.try_send(data).is_err() {
+            debug!("span export is behind, dropping span");
+        }
+    }
+}
+
+fn record_attributes(record: &ConnectionRecord) -> Vec<(&'static str, Value)> {
+    let mut attributes = vec![
+        ("direction", json!(format!("{:?}", record.direction))),
+        ("protocol", json!(format!("{:?}", record.protocol))),
+        ("source.address", json!(record.source.to_string())),
+        ("destination.address", json!(record.destination.to_string())),
+        ("bytes_sent", json!(record.bytes_sent)),
+        ("bytes_received", json!(record.bytes_received)),
+        (
+            "response_flags",
+            json!(record.close_reason.response_flags()),
+        ),
+    ];
+    if let Some(w) = &record.source_workload {
+        attributes.push(("source.workload", json!(w.name)));
+        attributes.push(("source.namespace", json!(w.namespace)));
+    }
+    if let Some(id) = &record.source_identity {
+        attributes.push(("source.principal", json!(id.to_string())));
+    }
+    if let Some(w) = &record.destination_workload {
+        attributes.push(("destination.workload", json!(w.name)));
+        attributes.push(("destination.namespace", json!(w.namespace)));
+    }
+    if let Some(id) = &record.destination_identity {
+        attributes.push(("destination.principal", json!(id.to_string())));
+    }
+    attributes
+}
+
+/// export sends spans to the collector at endpoint, batching spans that queue up while a request
+/// is in flight. Each request, including connecting, may take up to timeout.
+async fn export(endpoint: hyper::Uri, timeout: Duration, mut spans: mpsc::Receiver<SpanData>) {
+    let client = hyper::Client::new();
+    while let Some(span) = spans.recv().await {
+        let mut batch = vec![span];
+        while batch.len() < MAX_BATCH {
+            match spans.try_recv() {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+        let body = encode(&batch);
+        let req = hyper::Request::post(endpoint.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(body.to_string()))
+            .expect("export request is valid");
+        match tokio::time::timeout(timeout, client.request(req)).await {
+            Ok(Ok(res)) if res.status().is_success() => {}
+            Ok(Ok(res)) => warn!("failed to export {} spans: {}", batch.len(), res.status()),
+            Ok(Err(e)) => warn!("failed to export {} spans: {}", batch.len(), e),
+            Err(_) => warn!(
+                "failed to export {} spans: timed out after {:?}",
+                batch.len(),
+                timeout
+            ),
+        }
+    }
+}
+
+/// encode builds an OTLP ExportTraceServiceRequest in its JSON encoding.
+fn encode(spans: &[SpanData]) -> Value {
+    fn nanos(t: SystemTime) -> String {
+        t.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string()
+    }
+    fn attribute(key: &str, value: &Value) -> Value {
+        let value = match value {
+            Value::String(s) => json!({ "stringValue": s }),
+            // OTLP encodes 64 bit integers as strings in JSON.
+            Value::Number(n) => json!({ "intValue": n.to_string() }),
+            v => json!({ "stringValue": v.to_string() }),
+        };
+        json!({ "key": key, "value": value })
+    }
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|s| {
+            let mut span = json!({
+                "traceId": encode_hex(&s.ctx.trace_id),
+                "spanId": encode_hex(&s.ctx.span_id),
+                "name": s.name,
+                "kind": match s.kind {
+                    SpanKind::Server => 2,
+                    SpanKind::Client => 3,
+                },
+                "startTimeUnixNano": nanos(s.start),
+                "endTimeUnixNano": nanos(s.end),
+                "attributes": s.attributes.iter().map(|(k, v)| attribute(k, v)).collect::<Vec<_>>(),
+                "status": match &s.error {
+                    None => json!({ "code": 1 }),
+                    Some(message) => json!({ "code": 2, "message": message }),
+                },
+            });
+            if let Some(parent) = &s.parent_span_id {
+                span["parentSpanId"] = json!(encode_hex(parent));
+            }
+            if let Some(state) = &s.ctx.state {
+                span["traceState"] = json!(state);
+            }
+            span
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", &json!("ztunnel"))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "ztunnel" },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+fn decode_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+
+    use super::*;
+    use crate::proxy::Direction;
+    use crate::workload::Protocol;
+
+    #[test]
+    fn traceparent() {
+        let value = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let ctx = TraceContext::parse_traceparent(value).unwrap();
+        assert_eq!(
+            encode_hex(&ctx.trace_id),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(encode_hex(&ctx.span_id), "b7ad6b7169203331");
+        assert!(ctx.sampled);
+        assert_eq!(ctx.traceparent(), value);
+
+        for invalid in [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+        ] {
+            assert_eq!(TraceContext::parse_traceparent(invalid), None, "{invalid}");
+        }
+    }
+
+    /// collector starts a stub OTLP collector, returning its endpoint and the requests it receives.
+    /// The first unanswered requests are received, but never responded to.
+    async fn collector(unanswered: usize) -> (hyper::Uri, mpsc::Receiver<Value>) {
+        let (tx, rx) = mpsc::channel(10);
+        let received = Arc::new(AtomicUsize::new(0));
+        let service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            let received = received.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    let received = received.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        tx.send(serde_json::from_slice(&body).unwrap())
+                            .await
+                            .unwrap();
+                        if received.fetch_add(1, Ordering::SeqCst) < unanswered {
+                            futures::future::pending::<()>().await;
+                        }
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(service);
+        let endpoint = format!("http://{}/v1/traces", server.local_addr())
+            .parse()
+            .unwrap();
+        tokio::spawn(server);
+        (endpoint, rx)
+    }
+
+    #[tokio::test]
+    async fn export_spans() {
+        let (endpoint, mut requests) = collector(0).await;
+        let tracer = Tracer::new(endpoint, 1.0, Duration::from_secs(10));
+        let record = ConnectionRecord::new(
+            Direction::Outbound,
+            Protocol::Hbone,
+            "10.0.0.1:41000".parse().unwrap(),
+            "10.0.0.2:8080".parse().unwrap(),
+        );
+
+        let client = tracer.start("outbound", SpanKind::Client, None);
+        let client_ctx = client.context().unwrap();
+        assert!(client_ctx.sampled);
+        let server = tracer.start("inbound", SpanKind::Server, Some(client_ctx.clone()));
+        let server_ctx = server.context().unwrap();
+        assert_eq!(server_ctx.trace_id, client_ctx.trace_id);
+        assert_ne!(server_ctx.span_id, client_ctx.span_id);
+
+        server.end(&record);
+        let req = requests.recv().await.unwrap();
+        let span = &req["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], encode_hex(&client_ctx.trace_id));
+        assert_eq!(span["spanId"], encode_hex(&server_ctx.span_id));
+        assert_eq!(span["parentSpanId"], encode_hex(&client_ctx.span_id));
+        assert_eq!(span["name"], "inbound");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["status"]["code"], 1);
+        let attributes = span["attributes"].as_array().unwrap();
+        assert!(attributes.contains(&json!({
+            "key": "destination.address",
+            "value": { "stringValue": "10.0.0.2:8080" },
+        })));
+
+        // Unsampled traces are propagated, but not exported.
+        let unsampled = TraceContext {
+            sampled: false,
+            ..client_ctx
+        };
+        tracer
+            .start("inbound", SpanKind::Server, Some(unsampled))
+            .end(&record);
+        let mut record = record;
+        record.connect_failed("connection refused");
+        client.end(&record);
+        let req = requests.recv().await.unwrap();
+        let span = &req["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "outbound");
+        assert_eq!(span["parentSpanId"], Value::Null);
+        assert_eq!(span["status"]["code"], 2);
+        assert_eq!(span["status"]["message"], "connection refused");
+    }
+    #[tokio::test]
+    async fn export_timeout() {
+        let (endpoint, mut requests) = collector(1).await;
+        let tracer = Tracer::new(endpoint, 1.0, Duration::from_millis(100));
+        let record = ConnectionRecord::new(
+            Direction::Outbound,
+            Protocol::Tcp,
+            "10.0.0.1:41000".parse().unwrap(),
+            "10.0.0.2:8080".parse().unwrap(),
+        );
+
+        tracer
+            .start("outbound", SpanKind::Client, None)
+            .end(&record);
+        requests.recv().await.unwrap();
+        // The first request is never answered, yet later spans are still exported.
+        tracer
+            .start("outbound", SpanKind::Client, None)
+            .end(&record);
+        tokio::time::timeout(Duration::from_secs(5), requests.recv())
+            .await
+            .expect("export continues after a timeout")
+            .unwrap();
+    }
+}
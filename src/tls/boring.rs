@@ -195,6 +195,13 @@ impl Alpn {
     }
 }
 
+/// peer_identity returns the identity in the URI SAN of the certificate presented by the peer, if
+/// any.
+pub fn peer_identity(ssl: &ssl::SslRef) -> Option<identity::Identity> {
+    let names = ssl.peer_certificate()?.subject_alt_names()?;
+    names.iter().find_map(|name| name.uri()?.parse().ok())
+}
+
 #[async_trait::async_trait]
 pub trait CertProvider: Send + Sync + Clone {
     async fn fetch_cert(&self, fd: RawFd) -> Result<ssl::SslAcceptor, TlsError>;
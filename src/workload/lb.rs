@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rand::Rng;
+
+use crate::config;
+use crate::workload::{HealthStatus, Upstream, Workload};
+
+/// Strategy determines how an upstream is picked from the endpoints of a VIP.
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    Random,
+    RoundRobin,
+    LeastConnections,
+    PowerOfTwoChoices,
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::Random
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "round_robin" => Ok(Strategy::RoundRobin),
+            "least_connections" => Ok(Strategy::LeastConnections),
+            "power_of_two_choices" => Ok(Strategy::PowerOfTwoChoices),
+            s => Err(format!("unknown load balancing strategy {s}")),
+        }
+    }
+}
+
+/// Endpoints is the precomputed list of upstreams backing a VIP.
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(transparent)]
+pub struct Endpoints {
+    upstreams: Vec<Upstream>,
+    #[serde(skip)]
+    next: AtomicUsize,
+}
+
+impl Endpoints {
+    /// insert adds the upstream, replacing any existing upstream for the same workload and port.
+    pub fn insert(&mut self, us: Upstream) {
+        match self.upstreams.iter_mut().find(|existing| {
+            existing.workload.workload_ip == us.workload.workload_ip && existing.port == us.port
+        }) {
+            Some(existing) => *existing = us,
+            None => self.upstreams.push(us),
+        }
+    }
+
+    /// remove drops all upstreams for the workload.
+    pub fn remove(&mut self, workload_ip: &IpAddr) {
+        self.upstreams
+            .retain(|us| &us.workload.workload_ip != workload_ip);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upstreams.is_empty()
+    }
+
+    /// waypoint returns the waypoint serving the VIP, if any. A waypoint is a property of the
+    /// service, so it applies to every endpoint even if only some report it. Should endpoints
+    /// disagree, such as during a rollout, the lowest address is used so the choice does not depend
+    /// on the order endpoints were received in.
+    pub fn waypoint(&self) -> Option<IpAddr> {
+        self.upstreams
+            .iter()
+            .filter_map(|us| us.workload.waypoint_address)
+            .min()
+    }
+}
+
+/// LoadBalancer picks an upstream for a VIP, and tracks active connections to each upstream.
+/// It is designed to be cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct LoadBalancer {
+    strategy: Strategy,
+    locality_preference: bool,
+    active: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+}
+
+impl LoadBalancer {
+    pub fn new(cfg: &config::Config) -> LoadBalancer {
+        LoadBalancer {
+            strategy: cfg.lb_strategy,
+            locality_preference: cfg.lb_locality_preference,
+            active: Default::default(),
+        }
+    }
+
+    /// pick selects an upstream for a connection from source, avoiding any excluded endpoints.
+    /// Healthy upstreams are always preferred, followed by those closest to the source, if enabled.
+    /// Candidates are filtered in place, as this runs for every connection to a VIP.
+    pub fn pick<'a>(
+        &self,
+        endpoints: &'a Endpoints,
+        source: &Workload,
+        exclude: &[SocketAddr],
+    ) -> Option<&'a Upstream> {
+        let upstreams = &endpoints.upstreams;
+        // If every endpoint is excluded, let the caller decide whether reusing one is acceptable.
+        let all_excluded = upstreams.iter().all(|us| exclude.contains(&endpoint(us)));
+        let allowed = |us: &Upstream| all_excluded || !exclude.contains(&endpoint(us));
+        // Only fall back to unhealthy endpoints if there is nothing better to try.
+        let healthy_only = upstreams
+            .iter()
+            .any(|us| allowed(us) && us.workload.status == HealthStatus::Healthy);
+        let healthy = |us: &Upstream| !healthy_only || us.workload.status == HealthStatus::Healthy;
+        let best_locality = if self.locality_preference {
+            upstreams
+                .iter()
+                .filter(|us| allowed(us) && healthy(us))
+                .map(|us| locality_rank(source, &us.workload))
+                .max()
+        } else {
+            None
+        };
+        let candidates = || {
+            upstreams.iter().filter(move |us| {
+                allowed(us)
+                    && healthy(us)
+                    && best_locality
+                        .map_or(true, |best| locality_rank(source, &us.workload) == best)
+            })
+        };
+        let count = candidates().count();
+        if count == 0 {
+            return None;
+        }
+        match self.strategy {
+            Strategy::Random => candidates().nth(rand::thread_rng().gen_range(0..count)),
+            Strategy::RoundRobin => {
+                let next = endpoints.next.fetch_add(1, Ordering::Relaxed);
+                candidates().nth(next % count)
+            }
+            Strategy::LeastConnections => {
+                let active = self.active.lock().unwrap();
+                candidates().min_by_key(|us| active.get(&endpoint(us)).copied().unwrap_or_default())
+            }
+            Strategy::PowerOfTwoChoices => {
+                let mut rng = rand::thread_rng();
+                let first = rng.gen_range(0..count);
+                // A second, distinct candidate, if there is one.
+                let second = match count {
+                    1 => first,
+                    _ => (first + rng.gen_range(1..count)) % count,
+                };
+                let active = self.active.lock().unwrap();
+                [first, second]
+                    .into_iter()
+                    .filter_map(|i| candidates().nth(i))
+                    .min_by_key(|us| active.get(&endpoint(us)).copied().unwrap_or_default())
+            }
+        }
+    }
+
+    /// track records an active connection to the upstream address until the guard is dropped.
+    pub fn track(&self, addr: SocketAddr) -> ConnectionGuard {
+        *self.active.lock().unwrap().entry(addr).or_default() += 1;
+        ConnectionGuard {
+            addr,
+            active: self.active.clone(),
+        }
+    }
+}
+
+/// ConnectionGuard marks a connection to an upstream as active for as long as it is held.
+pub struct ConnectionGuard {
+    addr: SocketAddr,
+    active: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.addr);
+            }
+        }
+    }
+}
+
+fn endpoint(us: &Upstream) -> SocketAddr {
+    SocketAddr::from((us.workload.workload_ip, us.port))
+}
+
+/// locality_rank scores how close a workload is to the source; higher is closer.
+fn locality_rank(source: &Workload, wl: &Workload) -> u8 {
+    let (src, dst) = (&source.locality, &wl.locality);
+    if !source.node.is_empty() && source.node == wl.node {
+        4
+    } else if !src.region.is_empty() && src.region == dst.region {
+        if !src.zone.is_empty() && src.zone == dst.zone {
+            if !src.subzone.is_empty() && src.subzone == dst.subzone {
+                3
+            } else {
+                2
+            }
+        } else {
+            1
+        }
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::workload::Locality;
+
+    use super::*;
+
+    fn upstream(ip: [u8; 4], node: &str, zone: &str, status: HealthStatus) -> Upstream {
+        Upstream {
+            workload: Workload {
+                workload_ip: IpAddr::from(ip),
+                node: node.to_string(),
+                locality: Locality {
+                    region: "region".to_string(),
+                    zone: zone.to_string(),
+                    subzone: "".to_string(),
+                },
+                status,
+                ..source()
+            },
+            port: 80,
+        }
+    }
+
+    fn source() -> Workload {
+        Workload {
+            workload_ip: IpAddr::from([10, 0, 0, 1]),
+            waypoint_address: None,
+            gateway_ip: None,
+            protocol: Default::default(),
+            name: "source".to_string(),
+            namespace: "".to_string(),
+            service_account: "".to_string(),
+            workload_name: "".to_string(),
+            workload_type: "".to_string(),
+            canonical_name: "".to_string(),
+            canonical_revision: "".to_string(),
+            node: "node-a".to_string(),
+            locality: Locality {
+                region: "region".to_string(),
+                zone: "zone-a".to_string(),
+                subzone: "".to_string(),
+            },
+            status: HealthStatus::Healthy,
+            native_hbone: false,
+            client_waypoint_bypass_ports: vec![],
+        }
+    }
+
+    fn endpoints(upstreams: Vec<Upstream>) -> Endpoints {
+        let mut eps = Endpoints::default();
+        for us in upstreams {
+            eps.insert(us);
+        }
+        eps
+    }
+
+    fn lb(strategy: Strategy, locality_preference: bool) -> LoadBalancer {
+        LoadBalancer {
+            strategy,
+            locality_preference,
+            active: Default::default(),
+        }
+    }
+
+    fn pick(lb: &LoadBalancer, eps: &Endpoints) -> IpAddr {
+        lb.pick(eps, &source(), &[]).unwrap().workload.workload_ip
+    }
+
+    #[test]
+    fn insert_replaces() {
+        let mut eps = endpoints(vec![upstream([1, 1, 1, 1], "", "", HealthStatus::Healthy)]);
+        eps.insert(upstream([1, 1, 1, 1], "", "", HealthStatus::Unhealthy));
+        assert_eq!(eps.upstreams.len(), 1);
+        assert_eq!(eps.upstreams[0].workload.status, HealthStatus::Unhealthy);
+        eps.remove(&IpAddr::from([1, 1, 1, 1]));
+        assert!(eps.is_empty());
+    }
+
+    #[test]
+    fn waypoint() {
+        let with_waypoint = |ip: [u8; 4], waypoint: Option<[u8; 4]>| {
+            let mut us = upstream(ip, "", "", HealthStatus::Healthy);
+            us.workload.waypoint_address = waypoint.map(IpAddr::from);
+            us
+        };
+        let eps = endpoints(vec![
+            with_waypoint([1, 1, 1, 1], None),
+            with_waypoint([2, 2, 2, 2], Some([10, 0, 0, 2])),
+            with_waypoint([3, 3, 3, 3], Some([10, 0, 0, 1])),
+        ]);
+        assert_eq!(eps.waypoint(), Some(IpAddr::from([10, 0, 0, 1])));
+        let eps = endpoints(vec![with_waypoint([1, 1, 1, 1], None)]);
+        assert_eq!(eps.waypoint(), None);
+    }
+
+    #[test]
+    fn round_robin() {
+        let eps = endpoints(vec![
+            upstream([1, 1, 1, 1], "", "", HealthStatus::Healthy),
+            upstream([2, 2, 2, 2], "", "", HealthStatus::Healthy),
+        ]);
+        let lb = lb(Strategy::RoundRobin, false);
+        let first = pick(&lb, &eps);
+        let second = pick(&lb, &eps);
+        assert_ne!(first, second);
+        assert_eq!(pick(&lb, &eps), first);
+    }
+
+    #[test]
+    fn least_connections() {
+        let eps = endpoints(vec![
+            upstream([1, 1, 1, 1], "", "", HealthStatus::Healthy),
+            upstream([2, 2, 2, 2], "", "", HealthStatus::Healthy),
+        ]);
+        for strategy in [Strategy::LeastConnections, Strategy::PowerOfTwoChoices] {
+            let lb = lb(strategy, false);
+            let guard = lb.track("1.1.1.1:80".parse().unwrap());
+            assert_eq!(pick(&lb, &eps), IpAddr::from([2, 2, 2, 2]), "{strategy:?}");
+            drop(guard);
+            assert!(lb.active.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn skip_unhealthy() {
+        let eps = endpoints(vec![
+            upstream([1, 1, 1, 1], "", "", HealthStatus::Unhealthy),
+            upstream([2, 2, 2, 2], "", "", HealthStatus::Healthy),
+        ]);
+        let lb = lb(Strategy::RoundRobin, false);
+        for _ in 0..4 {
+            assert_eq!(pick(&lb, &eps), IpAddr::from([2, 2, 2, 2]));
+        }
+
+        let eps = endpoints(vec![upstream(
+            [1, 1, 1, 1],
+            "",
+            "",
+            HealthStatus::Unhealthy,
+        )]);
+        assert_eq!(pick(&lb, &eps), IpAddr::from([1, 1, 1, 1]), "fallback");
+    }
+
+    #[test]
+    fn exclude() {
+        let eps = endpoints(vec![
+            upstream([1, 1, 1, 1], "", "", HealthStatus::Healthy),
+            upstream([2, 2, 2, 2], "", "", HealthStatus::Healthy),
+        ]);
+        let lb = lb(Strategy::Random, false);
+        let failed = ["1.1.1.1:80".parse().unwrap()];
+        for _ in 0..4 {
+            let us = lb.pick(&eps, &source(), &failed).unwrap();
+            assert_eq!(us.workload.workload_ip, IpAddr::from([2, 2, 2, 2]));
+        }
+
+        let failed = ["1.1.1.1:80".parse().unwrap(), "2.2.2.2:80".parse().unwrap()];
+        assert!(lb.pick(&eps, &source(), &failed).is_some(), "all excluded");
+    }
+
+    #[test]
+    fn locality_preference() {
+        let eps = endpoints(vec![
+            upstream([1, 1, 1, 1], "", "zone-b", HealthStatus::Healthy),
+            upstream([2, 2, 2, 2], "", "zone-a", HealthStatus::Healthy),
+            upstream([3, 3, 3, 3], "", "zone-a", HealthStatus::Healthy),
+        ]);
+        let lb = lb(Strategy::RoundRobin, true);
+        for _ in 0..4 {
+            assert_ne!(pick(&lb, &eps), IpAddr::from([1, 1, 1, 1]), "same zone");
+        }
+
+        let eps = endpoints(vec![
+            upstream([1, 1, 1, 1], "", "zone-a", HealthStatus::Healthy),
+            upstream([2, 2, 2, 2], "node-a", "zone-b", HealthStatus::Healthy),
+        ]);
+        for _ in 0..4 {
+            assert_eq!(pick(&lb, &eps), IpAddr::from([2, 2, 2, 2]), "same node");
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::convert::Into;
 use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
@@ -6,7 +6,6 @@ use std::sync::{Arc, Mutex};
 use std::{fmt, net};
 
 use futures::future::TryFutureExt;
-use rand::prelude::IteratorRandom;
 use thiserror::Error;
 use tracing::{debug, error, info, warn};
 
@@ -17,6 +16,8 @@ use crate::workload::WorkloadError::ProtocolParse;
 use crate::xds::{Demander, HandlerContext, XdsUpdate};
 use crate::{config, xds};
 
+pub mod lb;
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum Protocol {
     Tcp,
@@ -41,6 +42,38 @@ impl TryFrom<Option<xds::istio::workload::Protocol>> for Protocol {
     }
 }
 
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub enum HealthStatus {
+    Healthy,
+    Unhealthy,
+}
+
+impl Default for HealthStatus {
+    fn default() -> Self {
+        HealthStatus::Healthy
+    }
+}
+
+impl From<xds::istio::workload::WorkloadStatus> for HealthStatus {
+    fn from(value: xds::istio::workload::WorkloadStatus) -> Self {
+        match value {
+            xds::istio::workload::WorkloadStatus::Healthy => HealthStatus::Healthy,
+            xds::istio::workload::WorkloadStatus::Unhealthy => HealthStatus::Unhealthy,
+        }
+    }
+}
+
+#[derive(Debug, Default, Hash, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Locality {
+    #[serde(default)]
+    pub region: String,
+    #[serde(default)]
+    pub zone: String,
+    #[serde(default)]
+    pub subzone: String,
+}
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Workload {
@@ -70,9 +103,18 @@ pub struct Workload {
 
     #[serde(default)]
     pub node: String,
+    #[serde(default)]
+    pub locality: Locality,
+    #[serde(default)]
+    pub status: HealthStatus,
 
     #[serde(default)]
     pub native_hbone: bool,
+
+    /// Ports of the workload whose traffic may skip the client's waypoint, as they need no L7
+    /// processing.
+    #[serde(default)]
+    pub client_waypoint_bypass_ports: Vec<u16>,
 }
 
 impl Workload {
@@ -84,6 +126,16 @@ impl Workload {
             service_account: self.service_account.clone(),
         }
     }
+
+    /// workload_name returns the name of the workload (such as a Deployment) the pod belongs to,
+    /// falling back to the pod name.
+    pub fn workload_name(&self) -> &str {
+        if self.workload_name.is_empty() {
+            &self.name
+        } else {
+            &self.workload_name
+        }
+    }
 }
 
 impl fmt::Display for Workload {
@@ -124,6 +176,11 @@ impl fmt::Display for Upstream {
     }
 }
 
+/// normalize_hostname makes hostnames case insensitive and strips any trailing dot.
+fn normalize_hostname(hostname: &str) -> String {
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
+
 fn byte_to_ip(b: &bytes::Bytes) -> Result<Option<IpAddr>, WorkloadError> {
     Ok(match b.len() {
         0 => None,
@@ -146,6 +203,7 @@ impl TryFrom<&XdsWorkload> for Workload {
         let waypoint = byte_to_ip(&resource.waypoint_address)?;
         let address = byte_to_ip(&resource.address)?.ok_or(WorkloadError::ByteAddressParse(0))?;
         let workload_type = resource.workload_type().as_str_name().to_lowercase();
+        let status = HealthStatus::from(resource.status());
         Ok(Workload {
             workload_ip: address,
             waypoint_address: waypoint,
@@ -166,6 +224,15 @@ impl TryFrom<&XdsWorkload> for Workload {
                 }
             },
             node: resource.node,
+            locality: resource
+                .locality
+                .map(|l| Locality {
+                    region: l.region,
+                    zone: l.zone,
+                    subzone: l.subzone,
+                })
+                .unwrap_or_default(),
+            status,
 
             workload_name: resource.workload_name,
             workload_type,
@@ -173,6 +240,11 @@ impl TryFrom<&XdsWorkload> for Workload {
             canonical_revision: resource.canonical_revision,
 
             native_hbone: resource.native_hbone,
+            client_waypoint_bypass_ports: resource
+                .client_waypoint_bypass_ports
+                .iter()
+                .map(|p| u16::try_from(*p).map_err(|_| WorkloadError::PortParse(*p)))
+                .collect::<Result<_, _>>()?,
         })
     }
 }
@@ -217,7 +289,11 @@ impl xds::Handler<XdsWorkload> for Arc<Mutex<WorkloadStore>> {
 
 impl WorkloadManager {
     pub fn new(config: config::Config) -> WorkloadManager {
-        let workloads: Arc<Mutex<WorkloadStore>> = Arc::new(Mutex::new(WorkloadStore::default()));
+        let workloads: Arc<Mutex<WorkloadStore>> = Arc::new(Mutex::new(WorkloadStore {
+            lb: lb::LoadBalancer::new(&config),
+            cluster_domain: config.cluster_domain.clone(),
+            ..Default::default()
+        }));
         let xds_workloads = workloads.clone();
         let xds_client = xds::Config::new(config.clone())
             .with_workload_handler(xds_workloads)
@@ -318,10 +394,36 @@ impl WorkloadInformation {
         wi.find_workload(addr).cloned()
     }
 
-    pub async fn find_upstream(&self, addr: SocketAddr) -> (Upstream, bool) {
+    /// find_upstream resolves the upstream for addr. If addr is a VIP, endpoints in exclude are
+    /// avoided unless there are no alternatives.
+    pub async fn find_upstream(
+        &self,
+        addr: SocketAddr,
+        source: &Workload,
+        exclude: &[SocketAddr],
+    ) -> (Upstream, bool) {
         let _ = self.fetch_workload(&addr.ip()).await;
         let wi = self.info.lock().unwrap();
-        wi.find_upstream(addr)
+        wi.find_upstream(addr, source, exclude)
+    }
+
+    /// find_waypoint returns the waypoint serving addr, which may be a VIP or a workload, if any.
+    pub fn find_waypoint(&self, addr: SocketAddr) -> Option<IpAddr> {
+        let wi = self.info.lock().unwrap();
+        wi.find_waypoint(addr)
+    }
+
+    /// find_hostname returns the addresses a service or workload hostname resolves to, if known.
+    pub fn find_hostname(&self, hostname: &str) -> Vec<IpAddr> {
+        let wi = self.info.lock().unwrap();
+        wi.find_hostname(hostname)
+    }
+
+    /// track_connection records an active connection to the upstream address, for load balancing,
+    /// until the returned guard is dropped.
+    pub fn track_connection(&self, addr: SocketAddr) -> lb::ConnectionGuard {
+        let wi = self.info.lock().unwrap();
+        wi.lb.track(addr)
     }
 }
 
@@ -329,13 +431,33 @@ impl WorkloadInformation {
 #[derive(serde::Serialize, Default, Debug)]
 pub struct WorkloadStore {
     workloads: HashMap<IpAddr, Workload>,
-    vips: HashMap<SocketAddr, HashSet<Upstream>>,
+    vips: HashMap<SocketAddr, lb::Endpoints>,
+    /// workload_vips maps a workload to the VIPs it is an endpoint of, so they can be updated
+    /// without scanning every VIP.
+    #[serde(skip)]
+    workload_vips: HashMap<IpAddr, Vec<SocketAddr>>,
+    /// hostnames maps DNS names to the addresses they resolve to, along with the workload each
+    /// address came from, as (workload, address).
+    #[serde(skip)]
+    hostnames: HashMap<String, Vec<(IpAddr, IpAddr)>>,
+    /// workload_hostnames is the reverse of hostnames, mapping a workload to the hostnames it
+    /// contributed addresses to, so they can be removed without scanning every hostname.
+    #[serde(skip)]
+    workload_hostnames: HashMap<IpAddr, Vec<String>>,
+    /// cluster_domain is the DNS domain workload hostnames are under.
+    #[serde(skip)]
+    cluster_domain: String,
+    #[serde(skip)]
+    lb: lb::LoadBalancer,
 }
 
 impl WorkloadStore {
     #[cfg(test)]
     pub fn test_store(workloads: Vec<XdsWorkload>) -> anyhow::Result<WorkloadStore> {
-        let mut store = WorkloadStore::default();
+        let mut store = WorkloadStore {
+            cluster_domain: "cluster.local".to_string(),
+            ..Default::default()
+        };
         for w in workloads {
             store.insert_xds_workload(w)?;
         }
@@ -344,7 +466,11 @@ impl WorkloadStore {
 
     fn insert_xds_workload(&mut self, w: XdsWorkload) -> anyhow::Result<()> {
         let workload = Workload::try_from(&w)?;
+        let wip = workload.workload_ip;
         self.insert(workload.clone());
+        // The update replaces the VIPs the workload is an endpoint of, so any it left, or whose
+        // target port changed, must not keep sending traffic to it.
+        self.remove_vips(&wip);
         for (vip, pl) in &w.virtual_ips {
             let ip = vip.parse::<IpAddr>()?;
             for port in &pl.ports {
@@ -354,16 +480,81 @@ impl WorkloadStore {
                     port: port.target_port as u16,
                 };
                 self.vips.entry(addr).or_default().insert(us);
+                let vips = self.workload_vips.entry(wip).or_default();
+                if !vips.contains(&addr) {
+                    vips.push(addr);
+                }
             }
         }
+        for (vip, hostname) in &w.service_hostnames {
+            let ip = vip.parse::<IpAddr>()?;
+            self.insert_hostname(hostname, workload.workload_ip, ip);
+        }
         Ok(())
     }
 
     fn insert(&mut self, w: Workload) {
         let wip = w.workload_ip;
+        self.remove_hostnames(&wip);
+        if !w.name.is_empty() && !w.namespace.is_empty() && !self.cluster_domain.is_empty() {
+            // Workloads are resolvable by <name>.<namespace>.svc.<cluster domain>, so they never
+            // shadow names outside of the cluster.
+            let hostname = format!("{}.{}.svc.{}", w.name, w.namespace, self.cluster_domain);
+            self.insert_hostname(&hostname, wip, wip);
+        }
         self.workloads.insert(wip, w);
     }
 
+    fn insert_hostname(&mut self, hostname: &str, workload: IpAddr, addr: IpAddr) {
+        let hostname = normalize_hostname(hostname);
+        let entries = self.hostnames.entry(hostname.clone()).or_default();
+        if !entries.contains(&(workload, addr)) {
+            entries.push((workload, addr));
+        }
+        let names = self.workload_hostnames.entry(workload).or_default();
+        if !names.contains(&hostname) {
+            names.push(hostname);
+        }
+    }
+
+    fn remove_hostnames(&mut self, workload: &IpAddr) {
+        for hostname in self.workload_hostnames.remove(workload).unwrap_or_default() {
+            if let Some(entries) = self.hostnames.get_mut(&hostname) {
+                entries.retain(|(wl, _)| wl != workload);
+                if entries.is_empty() {
+                    self.hostnames.remove(&hostname);
+                }
+            }
+        }
+    }
+
+    fn remove_vips(&mut self, workload: &IpAddr) {
+        for vip in self.workload_vips.remove(workload).unwrap_or_default() {
+            if let Some(endpoints) = self.vips.get_mut(&vip) {
+                endpoints.remove(workload);
+                if endpoints.is_empty() {
+                    self.vips.remove(&vip);
+                }
+            }
+        }
+    }
+
+    /// find_hostname returns the addresses hostname resolves to.
+    fn find_hostname(&self, hostname: &str) -> Vec<IpAddr> {
+        let mut addrs: Vec<IpAddr> = Vec::new();
+        for (_, addr) in self
+            .hostnames
+            .get(&normalize_hostname(hostname))
+            .into_iter()
+            .flatten()
+        {
+            if !addrs.contains(addr) {
+                addrs.push(*addr);
+            }
+        }
+        addrs
+    }
+
     fn remove(&mut self, ip: String) {
         use std::str::FromStr;
         let ip: IpAddr = match IpAddr::from_str(&ip) {
@@ -374,17 +565,35 @@ impl WorkloadStore {
             Ok(i) => i,
         };
         self.workloads.remove(&ip);
+        self.remove_hostnames(&ip);
+        self.remove_vips(&ip);
     }
 
     fn find_workload(&self, addr: &IpAddr) -> Option<&Workload> {
         self.workloads.get(addr)
     }
 
-    fn find_upstream(&self, addr: SocketAddr) -> (Upstream, bool) {
-        if let Some(upstream) = self.vips.get(&addr) {
-            // Randomly pick an upstream
-            // TODO: do this more efficiently, and not just randomly
-            let us: &Upstream = upstream.iter().choose(&mut rand::thread_rng()).unwrap();
+    fn find_waypoint(&self, addr: SocketAddr) -> Option<IpAddr> {
+        match self.vips.get(&addr) {
+            Some(endpoints) => endpoints.waypoint(),
+            None => self
+                .workloads
+                .get(&addr.ip())
+                .and_then(|wl| wl.waypoint_address),
+        }
+    }
+
+    fn find_upstream(
+        &self,
+        addr: SocketAddr,
+        source: &Workload,
+        exclude: &[SocketAddr],
+    ) -> (Upstream, bool) {
+        if let Some(us) = self
+            .vips
+            .get(&addr)
+            .and_then(|eps| self.lb.pick(eps, source, exclude))
+        {
             // TODO: avoid clone
             let mut us: Upstream = us.clone();
             Self::set_gateway_ip(&mut us);
@@ -412,6 +621,8 @@ impl WorkloadStore {
                     name: "".to_string(),
                     namespace: "".to_string(),
                     node: "".to_string(),
+                    locality: Default::default(),
+                    status: Default::default(),
                     service_account: "".to_string(),
                     workload_name: "".to_string(),
                     workload_type: "".to_string(),
@@ -419,6 +630,7 @@ impl WorkloadStore {
                     canonical_revision: "".to_string(),
 
                     native_hbone: false,
+                    client_waypoint_bypass_ports: vec![],
                 },
             },
             false,
@@ -450,6 +662,8 @@ pub enum WorkloadError {
     ByteAddressParse(usize),
     #[error("unknown protocol {0}")]
     ProtocolParse(String),
+    #[error("invalid port {0}")]
+    PortParse(u32),
 }
 
 #[cfg(test)]
@@ -563,8 +777,11 @@ mod tests {
             canonical_name: "".to_string(),
             canonical_revision: "".to_string(),
             node: "".to_string(),
+            locality: Default::default(),
+            status: Default::default(),
 
             native_hbone: false,
+            client_waypoint_bypass_ports: vec![],
         };
         let mut wi = WorkloadStore::default();
         assert_eq!((wi.workloads.len()), 0);
@@ -611,6 +828,90 @@ mod tests {
         assert_eq!(wi.workloads.len(), 0);
     }
 
+    #[test]
+    fn hostnames() {
+        let mut store = WorkloadStore::test_store(vec![XdsWorkload {
+            name: "pod".to_string(),
+            namespace: "ns".to_string(),
+            address: Bytes::copy_from_slice(&[127, 0, 0, 1]),
+            service_hostnames: HashMap::from([(
+                "10.0.0.1".to_string(),
+                "svc.ns.svc.cluster.local".to_string(),
+            )]),
+            ..Default::default()
+        }])
+        .unwrap();
+        let vip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(store.find_hostname("svc.ns.svc.cluster.local"), vec![vip]);
+        assert_eq!(
+            store.find_hostname("SVC.ns.svc.cluster.local."),
+            vec![vip],
+            "case insensitive, fully qualified"
+        );
+        assert_eq!(
+            store.find_hostname("pod.ns.svc.cluster.local"),
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+        );
+        assert!(
+            store.find_hostname("pod.ns").is_empty(),
+            "only under the cluster domain"
+        );
+        assert!(store
+            .find_hostname("unknown.ns.svc.cluster.local")
+            .is_empty());
+
+        store.remove("127.0.0.1".to_string());
+        assert!(store.find_hostname("svc.ns.svc.cluster.local").is_empty());
+        assert!(store.find_hostname("pod.ns.svc.cluster.local").is_empty());
+        assert!(store.hostnames.is_empty());
+        assert!(store.workload_hostnames.is_empty());
+    }
+
+    #[test]
+    fn vip_updates() {
+        let workload = |vips: &[(&str, u32)]| XdsWorkload {
+            name: "pod".to_string(),
+            namespace: "ns".to_string(),
+            address: Bytes::copy_from_slice(&[127, 0, 0, 1]),
+            virtual_ips: vips
+                .iter()
+                .map(|(vip, target_port)| {
+                    (
+                        vip.to_string(),
+                        xds::istio::workload::PortList {
+                            ports: vec![xds::istio::workload::Port {
+                                service_port: 80,
+                                target_port: *target_port,
+                            }],
+                        },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut store =
+            WorkloadStore::test_store(vec![workload(&[("10.0.0.1", 8080), ("10.0.0.2", 8080)])])
+                .unwrap();
+        assert_eq!(store.vips.len(), 2);
+
+        // Leaving a VIP, and changing the target port of another, replaces the old endpoints.
+        store
+            .insert_xds_workload(workload(&[("10.0.0.1", 9090)]))
+            .unwrap();
+        assert!(!store.vips.contains_key(&"10.0.0.2:80".parse().unwrap()));
+        let (us, is_vip) = store.find_upstream(
+            "10.0.0.1:80".parse().unwrap(),
+            &Workload::try_from(&workload(&[])).unwrap(),
+            &[],
+        );
+        assert!(is_vip);
+        assert_eq!(us.port, 9090);
+
+        store.remove("127.0.0.1".to_string());
+        assert!(store.vips.is_empty());
+        assert!(store.workload_vips.is_empty());
+    }
+
     #[tokio::test]
     async fn local_client() {
         let dir = std::path::PathBuf::from(std::env!("CARGO_MANIFEST_DIR"))
@@ -11,7 +11,7 @@ use crate::xds::istio::workload::Workload;
 use crate::xds::service::discovery::v3::aggregated_discovery_service_client::AggregatedDiscoveryServiceClient;
 use crate::xds::service::discovery::v3::Resource as ProtoResource;
 use crate::xds::service::discovery::v3::*;
-use crate::{tls, xds};
+use crate::{metrics, tls, xds};
 
 use super::Error;
 
@@ -163,12 +163,14 @@ impl AdsClient {
             let res = self.run_internal().await;
             match res {
                 Err(e @ Error::Connection(_)) => {
+                    terminated("connection_error");
                     // For connection errors, we add backoff
                     backoff = std::cmp::min(max_backoff, backoff * 2);
                     warn!("XDS client error: {}, retrying in {:?}", e, backoff);
                     tokio::time::sleep(backoff).await;
                 }
                 Err(e) => {
+                    terminated("error");
                     // For other errors, we connect immediately
                     // TODO: we may need more nuance here; if we fail due to invalid initial request we may overload
                     // But we want to reconnect from MaxConnectionAge immediately.
@@ -176,6 +178,7 @@ impl AdsClient {
                     backoff = Duration::from_millis(10);
                 }
                 Ok(_) => {
+                    terminated("complete");
                     warn!("XDS client complete");
                     backoff = Duration::from_millis(10);
                 }
@@ -335,6 +338,18 @@ impl AdsClient {
                     .join("; "),
             ),
         };
+        metrics::XDS_RESPONSES.inc(vec![
+            ("type_url", type_url.clone()),
+            (
+                "result",
+                if error_detail.is_some() {
+                    "nack"
+                } else {
+                    "ack"
+                }
+                .to_string(),
+            ),
+        ]);
         info!(
             type_url = type_url.clone(),
             none = response.nonce,
@@ -394,6 +409,11 @@ impl AdsClient {
     }
 }
 
+/// terminated counts the end of an XDS stream for reason.
+fn terminated(reason: &str) {
+    metrics::XDS_CONNECTION_TERMINATIONS.inc(vec![("reason", reason.to_string())]);
+}
+
 #[derive(Clone, Debug)]
 pub struct XdsResource<T: prost::Message> {
     pub name: String,